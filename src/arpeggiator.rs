@@ -0,0 +1,306 @@
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+use crate::clock::Transport;
+use crate::rng::Xoroshiro;
+use crate::synth_controller::SynthEvent;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "up" => Ok(Pattern::Up),
+            "down" => Ok(Pattern::Down),
+            "up-down" => Ok(Pattern::UpDown),
+            "random" => Ok(Pattern::Random),
+            "as-played" => Ok(Pattern::AsPlayed),
+            _ => Err(anyhow!("unknown arpeggiator pattern {}", s)),
+        }
+    }
+}
+
+/// Note length of the steps, as steps per beat.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rate(f64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let steps_per_beat = match s {
+            "1/4" => 1.0,
+            "1/8" => 2.0,
+            "1/8t" => 3.0,
+            "1/16" => 4.0,
+            "1/16t" => 6.0,
+            "1/32" => 8.0,
+            _ => return Err(anyhow!("unknown arpeggiator rate {}", s)),
+        };
+        Ok(Rate(steps_per_beat))
+    }
+}
+
+/// When steps play and how long their notes sound.
+#[derive(Debug, Copy, Clone)]
+pub struct StepTiming {
+    pub rate: Rate,
+    /// How long notes sound, as a fraction of their step.
+    pub gate: f32,
+    /// Delays every second step, 0 is straight and 1 puts it three quarters
+    /// into the pair.
+    pub swing: f32,
+    /// Beats per minute when not following the MIDI clock.
+    pub tempo: f32,
+    /// Follow the tempo and position of the MIDI clock when it runs.
+    pub sync: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ArpConfig {
+    pub pattern: Pattern,
+    pub octaves: u8,
+    pub timing: StepTiming,
+}
+
+/// A step that starts on this frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Counted from the start of the beat grid, so synced steps line up with
+    /// the bars of the song.
+    pub index: i64,
+    /// The first step since keys went down after all were released.
+    pub first: bool,
+}
+
+/// Takes held notes and plays notes for them on a grid of steps, the timing
+/// shared by the arpeggiator, sequencer and note repeat.
+pub struct Stepper {
+    timing: StepTiming,
+    sample_rate: f64,
+
+    // Held keys and velocities, in the order they were played.
+    held: Vec<(u8, f32)>,
+    restart: bool,
+
+    transport: Option<Transport>,
+    beat: f64,
+    last_step: Option<i64>,
+    sounding: Vec<u8>,
+    gate_end: f64,
+}
+
+impl Stepper {
+    pub fn new(timing: StepTiming, sample_rate: f32) -> Self {
+        Self {
+            timing: StepTiming {
+                gate: timing.gate.clamp(0.01, 1.0),
+                swing: timing.swing.clamp(0.0, 1.0),
+                ..timing
+            },
+            sample_rate: sample_rate as f64,
+            held: Vec::with_capacity(128),
+            restart: false,
+            transport: None,
+            beat: 0.0,
+            last_step: None,
+            sounding: Vec::with_capacity(128),
+            gate_end: 0.0,
+        }
+    }
+
+    /// Held keys and velocities, in the order they were played.
+    pub fn held(&self) -> &[(u8, f32)] {
+        &self.held
+    }
+
+    fn synced_transport(&self) -> Option<Transport> {
+        self.transport
+            .filter(|t| self.timing.sync && t.tempo.is_some())
+    }
+
+    /// Takes the notes, returns other events to pass on.
+    pub fn handle_event(&mut self, event: SynthEvent) -> Option<SynthEvent> {
+        match event {
+            SynthEvent::NoteOn { key, vel } => {
+                if self.held.is_empty() {
+                    self.restart = true;
+                }
+                self.held.retain(|&(k, _)| k != key);
+                self.held.push((key, vel));
+                None
+            }
+            SynthEvent::NoteOff { key } => {
+                self.held.retain(|&(k, _)| k != key);
+                None
+            }
+            SynthEvent::Transport { transport } => {
+                self.transport = Some(transport);
+                if self.timing.sync && transport.playing {
+                    self.beat = transport.beat;
+                }
+                Some(event)
+            }
+            event => Some(event),
+        }
+    }
+
+    /// Advances one sample, ending notes whose gate is over. Returns the step
+    /// that starts on this frame, if any, for the caller to `play` its notes.
+    pub fn step_frame(&mut self, emit: &mut impl FnMut(SynthEvent)) -> Option<Step> {
+        if self.held.is_empty() {
+            self.release(emit);
+            return None;
+        }
+
+        let synced = self.synced_transport();
+        let first = self.restart;
+        if self.restart {
+            self.restart = false;
+            match synced {
+                // Stay on the sequencer's grid, start at the next step.
+                Some(t) if t.playing => self.last_step = Some(self.step_at(self.beat).0),
+                _ => {
+                    self.beat = 0.0;
+                    self.last_step = None;
+                }
+            }
+        }
+
+        let (index, start, end) = self.step_at(self.beat);
+        let step = if self.last_step != Some(index) {
+            self.last_step = Some(index);
+            self.release(emit);
+            self.gate_end = start + self.timing.gate as f64 * (end - start);
+            Some(Step { index, first })
+        } else {
+            if self.beat >= self.gate_end {
+                self.release(emit);
+            }
+            None
+        };
+
+        let tempo = synced.and_then(|t| t.tempo).unwrap_or(self.timing.tempo);
+        self.beat += tempo as f64 / 60.0 / self.sample_rate;
+        step
+    }
+
+    /// Plays a note until the gate of the current step ends.
+    pub fn play(&mut self, key: u8, vel: f32, emit: &mut impl FnMut(SynthEvent)) {
+        emit(SynthEvent::NoteOn { key, vel });
+        self.sounding.push(key);
+    }
+
+    fn release(&mut self, emit: &mut impl FnMut(SynthEvent)) {
+        for key in self.sounding.drain(..) {
+            emit(SynthEvent::NoteOff { key });
+        }
+    }
+
+    /// The step at a position in beats, and where it starts and ends.
+    fn step_at(&self, beat: f64) -> (i64, f64, f64) {
+        let steps_per_beat = self.timing.rate.0;
+        let pairs = beat * steps_per_beat / 2.0;
+        let pair = pairs.floor();
+        let split = 0.5 + 0.25 * self.timing.swing as f64;
+        let to_beats = |pairs: f64| pairs * 2.0 / steps_per_beat;
+        if pairs - pair < split {
+            (2 * pair as i64, to_beats(pair), to_beats(pair + split))
+        } else {
+            (
+                2 * pair as i64 + 1,
+                to_beats(pair + split),
+                to_beats(pair + 1.0),
+            )
+        }
+    }
+}
+
+/// Turns held notes into a pattern of notes in time. Events other than
+/// notes pass through.
+pub struct Arpeggiator {
+    pattern: Pattern,
+    octaves: u8,
+    stepper: Stepper,
+    rng: Xoroshiro,
+
+    // The notes the pattern walks through, rebuilt on every step.
+    notes: Vec<(u8, f32)>,
+    counter: usize,
+}
+
+impl Arpeggiator {
+    pub fn new(config: ArpConfig, sample_rate: f32) -> Self {
+        Self {
+            pattern: config.pattern,
+            octaves: config.octaves.clamp(1, 4),
+            stepper: Stepper::new(config.timing, sample_rate),
+            rng: Xoroshiro::new(42),
+            notes: Vec::with_capacity(128 * 4),
+            counter: 0,
+        }
+    }
+
+    /// Takes the notes, returns other events to pass on.
+    pub fn handle_event(&mut self, event: SynthEvent) -> Option<SynthEvent> {
+        self.stepper.handle_event(event)
+    }
+
+    /// Advances one sample, emitting the notes that start or end.
+    pub fn step_frame(&mut self, mut emit: impl FnMut(SynthEvent)) {
+        if let Some(step) = self.stepper.step_frame(&mut emit) {
+            if step.first {
+                self.counter = 0;
+            }
+            let (key, vel) = self.next_note();
+            self.stepper.play(key, vel, &mut emit);
+        }
+    }
+
+    fn next_note(&mut self) -> (u8, f32) {
+        self.notes.clear();
+        let mut base_start = 0;
+        for octave in 0..self.octaves {
+            for &(key, vel) in self.stepper.held() {
+                let key = key as u32 + 12 * octave as u32;
+                if key < 128 {
+                    self.notes.push((key as u8, vel));
+                }
+            }
+            if self.pattern != Pattern::AsPlayed {
+                self.notes[base_start..].sort_by_key(|&(key, _)| key);
+            }
+            base_start = self.notes.len();
+        }
+
+        let n = self.notes.len();
+        let c = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+        let idx = match self.pattern {
+            Pattern::Up | Pattern::AsPlayed => c % n,
+            Pattern::Down => n - 1 - c % n,
+            Pattern::UpDown if n == 1 => 0,
+            Pattern::UpDown => {
+                // Don't repeat the top and bottom notes.
+                let period = 2 * n - 2;
+                let i = c % period;
+                if i < n {
+                    i
+                } else {
+                    period - i
+                }
+            }
+            Pattern::Random => (self.rng.next() % n as u64) as usize,
+        };
+        self.notes[idx]
+    }
+}
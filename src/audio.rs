@@ -1,43 +1,61 @@
 use anyhow::Result;
-
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use cpal::traits::*;
+use cpal::SampleFormat;
 
-use crate::synth::Synth;
-use crate::synth_controller::SynthController;
+use crate::dither::Dither;
+use crate::limiter::Limiter;
+use crate::synth_controller::Part;
+use crate::util::DenormalGuard;
 
 
-pub fn run<T, S: Synth + 'static>(
+pub fn run<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
-    mut synth: S,
-    mut synth_controller: SynthController<S>,
-) -> Result<cpal::Stream>
+    mut parts: Vec<Box<dyn Part>>,
+    noise_shaping: bool,
+) -> Result<(cpal::Stream, Arc<AtomicBool>)>
 where
     T: cpal::Sample,
 {
     let channels = config.channels as usize;
+    let mut limiter = Limiter::new(config.sample_rate.0 as f32);
+    let clip_indicator = limiter.clip_indicator();
+    let mut dither = match T::FORMAT {
+        SampleFormat::F32 => None,
+        SampleFormat::I16 | SampleFormat::U16 => Some(Dither::new(16, noise_shaping)),
+    };
     let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
     let stream = device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            synth_controller.pump_events(&mut synth);
-            synth.notify_buffer();
+            let _denormal_guard = DenormalGuard::new();
+            for part in parts.iter_mut() {
+                part.begin_buffer();
+            }
+            let mut step_frame = || {
+                parts.iter_mut().fold((0.0, 0.0), |(l, r), part| {
+                    let (pl, pr) = part.step_frame();
+                    (l + pl, r + pr)
+                })
+            };
 
 
             if channels == 1 {
                 for sample in data.iter_mut() {
-                    synth.step_frame();
-                    let (l, r) = synth_controller.step_all_voices(&mut synth);
-                    *sample = cpal::Sample::from(&((l + r) / 2.0));
+                    let (l, r) = step_frame();
+                    let (l, r) = limiter.process(l, r);
+                    *sample = to_sample(&mut dither, 0, (l + r) / 2.0);
                 }
             } else if channels == 2 {
                 for frame in data.chunks_mut(2) {
-                    synth.step_frame();
-                    let (l, r) = synth_controller.step_all_voices(&mut synth);
-                    frame[0] = cpal::Sample::from(&l);
-                    frame[1] = cpal::Sample::from(&r);
+                    let (l, r) = step_frame();
+                    let (l, r) = limiter.process(l, r);
+                    frame[0] = to_sample(&mut dither, 0, l);
+                    frame[1] = to_sample(&mut dither, 1, r);
                 }
             } else {
                 panic!("can't output to more than 2 channels");
@@ -47,5 +65,12 @@ where
     )?;
     stream.play()?;
 
-    Ok(stream)
+    Ok((stream, clip_indicator))
+}
+
+fn to_sample<T: cpal::Sample>(dither: &mut Option<Dither>, channel: usize, x: f32) -> T {
+    match dither {
+        Some(d) => cpal::Sample::from(&(d.process(channel, x) as i16)),
+        None => cpal::Sample::from(&x),
+    }
 }
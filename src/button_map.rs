@@ -0,0 +1,347 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::params::{ParamId, ParamInfo};
+
+/// What drives a parameter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    Controller(u8),
+    /// The MSB on controller 0-31, the LSB on the controller 32 higher.
+    Controller14(u8),
+    Nrpn(u16),
+}
+
+impl Source {
+    /// Whether the source listens to this 7-bit controller.
+    fn uses_controller(self, controller: u8) -> bool {
+        match self {
+            Source::Controller(cc) => cc == controller,
+            Source::Controller14(cc) => cc == controller || cc + 32 == controller,
+            Source::Nrpn(_) => false,
+        }
+    }
+}
+
+/// How controller values move a parameter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// The value is the position.
+    #[default]
+    Absolute,
+    /// Endless encoders sending steps: 1 to 63 up, 127 down to 64 as -1 to -64.
+    RelativeTwosComplement,
+    /// Endless encoders sending steps with the sign in bit 6: 65 is -1.
+    RelativeSignedBit,
+    /// Endless encoders sending steps around 64: 65 is +1, 63 is -1.
+    RelativeBinaryOffset,
+    /// Buttons, each press flips between the ends of the range.
+    Toggle,
+    /// Buttons, at the top of the range while held.
+    Momentary,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Absolute => "absolute",
+            Mode::RelativeTwosComplement => "relative-twos-complement",
+            Mode::RelativeSignedBit => "relative-signed-bit",
+            Mode::RelativeBinaryOffset => "relative-binary-offset",
+            Mode::Toggle => "toggle",
+            Mode::Momentary => "momentary",
+        }
+    }
+
+    /// Decodes the steps sent by a relative control, for values of
+    /// `resolution + 1` possibilities. `None` for the other modes.
+    pub fn relative_steps(self, value: u16, resolution: u16) -> Option<i32> {
+        let half = (resolution as i32 + 1) / 2;
+        let value = value as i32;
+        match self {
+            Mode::RelativeTwosComplement if value >= half => Some(value - 2 * half),
+            Mode::RelativeTwosComplement => Some(value),
+            Mode::RelativeSignedBit if value >= half => Some(-(value - half)),
+            Mode::RelativeSignedBit => Some(value),
+            Mode::RelativeBinaryOffset => Some(value - half),
+            _ => None,
+        }
+    }
+}
+
+/// A button map entry. In the TOML file it is either a plain controller
+/// number, or an inline table with one of `cc`, `cc14` or `nrpn` and
+/// optionally a `mode`, a `min` and `max` in the parameter's units and
+/// `invert`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mapping {
+    pub source: Source,
+    pub mode: Mode,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub invert: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingSpec {
+    cc: Option<u8>,
+    cc14: Option<u8>,
+    nrpn: Option<u16>,
+    #[serde(default)]
+    mode: Mode,
+    min: Option<f32>,
+    max: Option<f32>,
+    #[serde(default)]
+    invert: bool,
+}
+
+impl Mapping {
+    pub fn controller(cc: u8) -> Self {
+        Self {
+            source: Source::Controller(cc),
+            mode: Mode::Absolute,
+            min: None,
+            max: None,
+            invert: false,
+        }
+    }
+
+    fn from_toml(value: toml::Value) -> Result<Self> {
+        let spec = match value {
+            toml::Value::Integer(cc) => MappingSpec {
+                cc: Some(u8::try_from(cc)?),
+                ..Default::default()
+            },
+            value => value.try_into::<MappingSpec>()?,
+        };
+        let source = match (spec.cc, spec.cc14, spec.nrpn) {
+            (Some(cc), None, None) if cc < 128 => Source::Controller(cc),
+            (None, Some(cc), None) if cc < 32 => Source::Controller14(cc),
+            (None, None, Some(nrpn)) if nrpn < 1 << 14 => Source::Nrpn(nrpn),
+            _ => bail!("expected exactly one of cc < 128, cc14 < 32 or nrpn < 16384"),
+        };
+        Ok(Self {
+            source,
+            mode: spec.mode,
+            min: spec.min,
+            max: spec.max,
+            invert: spec.invert,
+        })
+    }
+
+    /// The TOML value for this mapping, a plain number if possible.
+    fn to_toml(self) -> String {
+        let mut fields = vec![match self.source {
+            Source::Controller(cc) if self == Self::controller(cc) => return cc.to_string(),
+            Source::Controller(cc) => format!("cc = {}", cc),
+            Source::Controller14(cc) => format!("cc14 = {}", cc),
+            Source::Nrpn(nrpn) => format!("nrpn = {}", nrpn),
+        }];
+        if self.mode != Mode::Absolute {
+            fields.push(format!("mode = \"{}\"", self.mode.name()));
+        }
+        if let Some(min) = self.min {
+            fields.push(format!("min = {:?}", min));
+        }
+        if let Some(max) = self.max {
+            fields.push(format!("max = {:?}", max));
+        }
+        if self.invert {
+            fields.push("invert = true".to_owned());
+        }
+        format!("{{ {} }}", fields.join(", "))
+    }
+
+    fn bind(&self, param: ParamId, info: &'static ParamInfo) -> Binding {
+        let low = self.min.map_or(0.0, |min| info.normalize(min));
+        let high = self.max.map_or(1.0, |max| info.normalize(max));
+        let (low, high) = if self.invert {
+            (high, low)
+        } else {
+            (low, high)
+        };
+        Binding {
+            param,
+            info,
+            mode: self.mode,
+            low,
+            high,
+        }
+    }
+}
+
+/// Assigns MIDI controllers to parameters by name, loaded from a TOML file
+/// of `name = mapping` entries. One file can serve all engines, each engine
+/// picks the names it knows.
+#[derive(Debug, Clone)]
+pub struct ButtonMap {
+    path: String,
+    controllers: HashMap<String, Mapping>,
+}
+
+impl ButtonMap {
+    pub fn from_toml(fname: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(fname)?;
+        let entries: HashMap<String, toml::Value> = toml::from_str(&contents)
+            .map_err(|e| anyhow!("could not parse button map {}: {}", fname, e))?;
+        let controllers = entries
+            .into_iter()
+            .map(|(name, value)| match Mapping::from_toml(value) {
+                Ok(mapping) => Ok((name, mapping)),
+                Err(e) => Err(anyhow!("invalid mapping for {} in {}: {}", name, fname, e)),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            path: fname.to_owned(),
+            controllers,
+        })
+    }
+
+    pub fn mapping(&self, name: &str) -> Option<&Mapping> {
+        self.controllers.get(name)
+    }
+
+    /// Names assigned to a 7-bit controller, including 14-bit pairs using it.
+    pub fn names_for(&self, controller: u8) -> impl Iterator<Item = &str> {
+        self.controllers
+            .iter()
+            .filter(move |(_, m)| m.source.uses_controller(controller))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Assigns a controller to a name and writes the assignment back to the
+    /// TOML file. Only the line for that name changes, so comments and layout
+    /// survive, and options of an existing mapping are kept. The assignment
+    /// takes effect even if the file can't be written.
+    pub fn assign(&mut self, name: &str, controller: u8) -> Result<()> {
+        let mapping = match self.mapping(name) {
+            Some(m) => Mapping {
+                source: Source::Controller(controller),
+                ..*m
+            },
+            None => Mapping::controller(controller),
+        };
+        self.controllers.insert(name.to_owned(), mapping);
+
+        let contents = std::fs::read_to_string(&self.path)?;
+        let mut found = false;
+        let mut lines: Vec<String> = contents
+            .lines()
+            .map(|line| {
+                let (entry, comment) = match line.find('#') {
+                    Some(idx) => line.split_at(idx),
+                    None => (line, ""),
+                };
+                let is_entry = entry
+                    .split_once('=')
+                    .is_some_and(|(key, _)| key.trim() == name);
+                if !is_entry {
+                    return line.to_owned();
+                }
+
+                found = true;
+                let indent = &entry[..entry.len() - entry.trim_start().len()];
+                let mut new_line = format!("{}{} = {}", indent, name, mapping.to_toml());
+                if !comment.is_empty() {
+                    new_line.push(' ');
+                    new_line.push_str(comment);
+                }
+                new_line
+            })
+            .collect();
+        if !found {
+            lines.push(format!("{} = {}", name, mapping.to_toml()));
+        }
+
+        let mut new_contents = lines.join("\n");
+        new_contents.push('\n');
+        std::fs::write(&self.path, new_contents)?;
+        Ok(())
+    }
+
+    /// Looks up which parameter each controller drives.
+    pub fn resolve(&self, params: &'static [ParamInfo]) -> ParamMap {
+        let mut by_controller = [None; 128];
+        let mut by_controller14 = [None; 32];
+        let mut by_nrpn = HashMap::new();
+        let mut sources = vec![None; params.len()];
+        for (idx, info) in params.iter().enumerate() {
+            let mapping = match self.mapping(info.name) {
+                Some(m) => m,
+                None => continue,
+            };
+            sources[idx] = Some(mapping.source);
+            let binding = mapping.bind(ParamId(idx), info);
+            match mapping.source {
+                Source::Controller(cc) => by_controller[cc as usize] = Some(binding),
+                Source::Controller14(cc) => by_controller14[cc as usize] = Some(binding),
+                Source::Nrpn(nrpn) => {
+                    by_nrpn.insert(nrpn, binding);
+                }
+            }
+        }
+        ParamMap {
+            params,
+            by_controller,
+            by_controller14,
+            by_nrpn,
+            sources,
+        }
+    }
+}
+
+/// A parameter driven by a controller, with the range the controller covers
+/// as normalized positions of the parameter. An inverted mapping has `low`
+/// above `high`.
+#[derive(Debug, Copy, Clone)]
+pub struct Binding {
+    pub param: ParamId,
+    pub info: &'static ParamInfo,
+    pub mode: Mode,
+    pub low: f32,
+    pub high: f32,
+}
+
+/// Controller to parameter lookup for one engine.
+#[derive(Debug, Clone)]
+pub struct ParamMap {
+    params: &'static [ParamInfo],
+    by_controller: [Option<Binding>; 128],
+    by_controller14: [Option<Binding>; 32],
+    by_nrpn: HashMap<u16, Binding>,
+    sources: Vec<Option<Source>>,
+}
+
+impl ParamMap {
+    pub fn params(&self) -> &'static [ParamInfo] {
+        self.params
+    }
+
+    pub fn lookup(&self, controller: u8) -> Option<Binding> {
+        *self.by_controller.get(controller as usize)?
+    }
+
+    /// Looks up a 14-bit controller pair by the controller of its MSB.
+    pub fn lookup14(&self, msb_controller: u8) -> Option<Binding> {
+        *self.by_controller14.get(msb_controller as usize)?
+    }
+
+    pub fn lookup_nrpn(&self, nrpn: u16) -> Option<Binding> {
+        self.by_nrpn.get(&nrpn).copied()
+    }
+
+    /// The source driving a parameter and its binding, if it is assigned.
+    pub fn binding_for(&self, param: ParamId) -> Option<(Source, Binding)> {
+        let source = (*self.sources.get(param.0)?)?;
+        let binding = match source {
+            Source::Controller(cc) => self.lookup(cc),
+            Source::Controller14(cc) => self.lookup14(cc),
+            Source::Nrpn(nrpn) => self.lookup_nrpn(nrpn),
+        }?;
+        Some((source, binding))
+    }
+}
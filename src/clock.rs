@@ -0,0 +1,105 @@
+use crate::midi::{Event, EventContent};
+
+/// MIDI clock runs at 24 ticks per quarter note.
+pub const TICKS_PER_BEAT: u64 = 24;
+
+/// Song position pointers count sixteenth notes.
+const TICKS_PER_SONG_POSITION: u64 = 6;
+
+/// Gaps longer than this mean the clock stopped and restarted, in
+/// microseconds.
+const MAX_TICK_INTERVAL: f64 = 250_000.0;
+
+/// Tempo and transport of an external sequencer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transport {
+    pub playing: bool,
+    /// In beats per minute, `None` until the clock has run for a bit.
+    pub tempo: Option<f32>,
+    /// Position in quarter notes since the start of the song.
+    pub beat: f64,
+}
+
+/// Follows the MIDI clock and transport messages of a sequencer.
+#[derive(Debug, Clone)]
+pub struct MidiClock {
+    playing: bool,
+    next_tick: u64,
+    last_tick_time: Option<u64>,
+    ticks_timed: u32,
+    // Smoothed time between ticks, in microseconds.
+    tick_interval: f64,
+}
+
+impl MidiClock {
+    pub fn new() -> Self {
+        Self {
+            playing: false,
+            next_tick: 0,
+            last_tick_time: None,
+            ticks_timed: 0,
+            tick_interval: 0.0,
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        Transport {
+            playing: self.playing,
+            tempo: self.tempo(),
+            beat: self.next_tick.saturating_sub(1) as f64 / TICKS_PER_BEAT as f64,
+        }
+    }
+
+    /// Wait a beat before trusting the tempo, so the estimate has settled.
+    fn tempo(&self) -> Option<f32> {
+        if self.ticks_timed < TICKS_PER_BEAT as u32 {
+            return None;
+        }
+        Some((60_000_000.0 / (self.tick_interval * TICKS_PER_BEAT as f64)) as f32)
+    }
+
+    /// Returns true if the event was a clock or transport message.
+    pub fn handle_midi_event(&mut self, event: &Event) -> bool {
+        match event.content {
+            EventContent::Clock => {
+                self.time_tick(event.timestamp);
+                if self.playing {
+                    self.next_tick += 1;
+                }
+            }
+            EventContent::Start => {
+                self.playing = true;
+                self.next_tick = 0;
+            }
+            EventContent::Continue => self.playing = true,
+            EventContent::Stop => self.playing = false,
+            EventContent::SongPosition { position } => {
+                self.next_tick = position as u64 * TICKS_PER_SONG_POSITION;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn time_tick(&mut self, timestamp: u64) {
+        let interval = self
+            .last_tick_time
+            .map(|last| timestamp.saturating_sub(last) as f64);
+        self.last_tick_time = Some(timestamp);
+
+        match interval {
+            Some(interval) if interval > 0.0 && interval < MAX_TICK_INTERVAL => {
+                // Average over about a beat to smooth out the jitter of
+                // clocks sent from software.
+                if self.ticks_timed == 0 {
+                    self.tick_interval = interval;
+                } else {
+                    let coef = 1.0 / TICKS_PER_BEAT as f64;
+                    self.tick_interval += coef * (interval - self.tick_interval);
+                }
+                self.ticks_timed = self.ticks_timed.saturating_add(1);
+            }
+            _ => self.ticks_timed = 0,
+        }
+    }
+}
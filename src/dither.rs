@@ -0,0 +1,52 @@
+use crate::rng::Xoroshiro;
+
+const MAX_CHANNELS: usize = 2;
+
+/// Converts floating point samples to integers of a given bit depth with TPDF
+/// dither and optional second-order noise shaping.
+pub struct Dither {
+    rng: Xoroshiro,
+    scale: f32,
+    min: f32,
+    max: f32,
+    noise_shaping: bool,
+    // The last two quantization errors per channel, most recent first.
+    errors: [[f32; 2]; MAX_CHANNELS],
+}
+
+impl Dither {
+    pub fn new(bits: u32, noise_shaping: bool) -> Self {
+        let scale = (1u64 << (bits - 1)) as f32;
+        Self {
+            rng: Xoroshiro::new(0x5eed),
+            scale,
+            min: -scale,
+            max: scale - 1.0,
+            noise_shaping,
+            errors: [[0.0; 2]; MAX_CHANNELS],
+        }
+    }
+
+    /// Quantizes a sample in [-1, 1] for the given channel.
+    pub fn process(&mut self, channel: usize, sample: f32) -> i32 {
+        let errors = &mut self.errors[channel];
+        let mut v = sample * self.scale;
+        if self.noise_shaping {
+            // Error feedback with noise transfer function (1 - z^-1)^2, which
+            // moves the noise floor towards Nyquist where hearing is least
+            // sensitive.
+            v += -2.0 * errors[0] + errors[1];
+        }
+
+        // Triangular PDF noise of +- 1 LSB is the difference of two uniforms.
+        let tpdf = self.rng.next_float() - self.rng.next_float();
+        let rounded = (v + tpdf).round();
+
+        // Feed back the requantization error only, within +- 1.5 LSB. The
+        // error of clipping can be arbitrarily large and would make the
+        // shaping loop run away.
+        errors[1] = errors[0];
+        errors[0] = rounded - v;
+        rounded.clamp(self.min, self.max) as i32
+    }
+}
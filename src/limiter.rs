@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::util::*;
+
+// 4x oversampling interpolation filter for true-peak detection, a windowed
+// sinc split in the three fractional phases (the zero phase is the input).
+const TP_TAPS: usize = 8;
+const TP_PHASES: [[f32; TP_TAPS]; 3] = [
+    [
+        -0.0124, 0.0500, -0.1479, 0.8936, 0.2801, -0.0863, 0.0269, -0.0040,
+    ],
+    [
+        -0.0106, 0.0526, -0.1591, 0.6171, 0.6171, -0.1591, 0.0526, -0.0106,
+    ],
+    [
+        -0.0040, 0.0269, -0.0863, 0.2801, 0.8936, -0.1479, 0.0500, -0.0124,
+    ],
+];
+
+/// Estimates the inter-sample peak of a single channel.
+struct TruePeakDetector {
+    history: [f32; TP_TAPS],
+}
+
+impl TruePeakDetector {
+    fn new() -> Self {
+        Self {
+            history: [0.0; TP_TAPS],
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.history.copy_within(1.., 0);
+        self.history[TP_TAPS - 1] = sample;
+
+        let mut peak = self.history[TP_TAPS / 2 - 1].abs();
+        for phase in TP_PHASES.iter() {
+            let interp: f32 = phase.iter().zip(&self.history).map(|(c, x)| c * x).sum();
+            peak = peak.max(interp.abs());
+        }
+        peak
+    }
+}
+
+/// Final-stage stereo-linked lookahead true-peak limiter.
+///
+/// The required gain is held at its minimum over the lookahead window and
+/// then averaged over the window, which ramps the gain down exactly in time
+/// for the peak to leave the delay line without overshoot.
+pub struct Limiter {
+    ceiling_db: f32,
+    knee_db: f32,
+    ceiling: f32,
+    rel_coef: f32,
+
+    detectors: (TruePeakDetector, TruePeakDetector),
+    delay_line: Vec<(f32, f32)>,
+    delay_idx: usize,
+    // Sliding window minimum of the required gain: increasing gains with
+    // the frame they were seen at, the front being the minimum.
+    hold_queue: VecDeque<(u64, f32)>,
+    hold_len: u64,
+    frame: u64,
+    envelope: f32,
+    avg_history: Vec<f32>,
+    avg_idx: usize,
+    avg_sum: f64,
+
+    clip_indicator: Arc<AtomicBool>,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f32) -> Self {
+        let lookahead = ((sample_rate * 1.5 / 1000.0) as usize).max(1);
+
+        // The detector reports peaks TP_TAPS / 2 samples late, and the hold
+        // window is one longer than the averaging window so the average never
+        // includes a gain from after the peak's hold has ended.
+        let delay = lookahead + TP_TAPS / 2;
+        let mut limiter = Self {
+            ceiling_db: 0.0,
+            knee_db: 3.0,
+            ceiling: 1.0,
+            rel_coef: (-1.0 / (0.05 * sample_rate)).exp(),
+
+            detectors: (TruePeakDetector::new(), TruePeakDetector::new()),
+            delay_line: vec![(0.0, 0.0); delay],
+            delay_idx: 0,
+            // Never holds more than the window plus the newest gain, so it
+            // doesn't allocate while processing.
+            hold_queue: VecDeque::with_capacity(lookahead + 2),
+            hold_len: lookahead as u64 + 1,
+            frame: 0,
+            envelope: 1.0,
+            avg_history: vec![1.0; lookahead],
+            avg_idx: 0,
+            avg_sum: lookahead as f64,
+
+            clip_indicator: Arc::new(AtomicBool::new(false)),
+        };
+        limiter.set_ceiling(-1.0);
+        limiter
+    }
+
+    /// Set the true-peak ceiling in dBTP.
+    pub fn set_ceiling(&mut self, ceiling_db: f32) {
+        self.ceiling_db = ceiling_db;
+        self.ceiling = ceiling_db.db_to_gain();
+    }
+
+    /// Set the width of the soft knee in db, centered around the ceiling.
+    pub fn set_knee(&mut self, knee_db: f32) {
+        self.knee_db = knee_db.max(0.0);
+    }
+
+    /// A flag that is raised whenever the input exceeds 0 dBFS. The reader is
+    /// expected to reset it after reporting.
+    pub fn clip_indicator(&self) -> Arc<AtomicBool> {
+        self.clip_indicator.clone()
+    }
+
+    /// Gain (linear) needed to bring a peak of the given level under the ceiling.
+    fn required_gain(&self, peak: f32) -> f32 {
+        let level_db = peak.max(1e-9).gain_to_db();
+        let over = level_db - self.ceiling_db;
+        let reduction_db = if 2.0 * over <= -self.knee_db {
+            0.0
+        } else if 2.0 * over < self.knee_db {
+            let x = over + self.knee_db / 2.0;
+            -x * x / (2.0 * self.knee_db)
+        } else {
+            -over
+        };
+        reduction_db.db_to_gain()
+    }
+
+    pub fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let peak = self.detectors.0.process(l).max(self.detectors.1.process(r));
+        if peak > 1.0 {
+            self.clip_indicator.store(true, Ordering::Relaxed);
+        }
+
+        // Hold the minimum required gain over the lookahead window.
+        let required = self.required_gain(peak);
+        while self.hold_queue.back().is_some_and(|&(_, g)| g >= required) {
+            self.hold_queue.pop_back();
+        }
+        self.hold_queue.push_back((self.frame, required));
+        while self.hold_queue[0].0 + self.hold_len <= self.frame {
+            self.hold_queue.pop_front();
+        }
+        let held = self.hold_queue[0].1;
+        self.frame += 1;
+
+        // Instant attack into the averaging stage, exponential release.
+        self.envelope = if held < self.envelope {
+            held
+        } else {
+            self.rel_coef.mix(held, self.envelope)
+        };
+
+        self.avg_sum += (self.envelope - self.avg_history[self.avg_idx]) as f64;
+        self.avg_history[self.avg_idx] = self.envelope;
+        self.avg_idx = (self.avg_idx + 1) % self.avg_history.len();
+        let gain = (self.avg_sum / self.avg_history.len() as f64) as f32;
+
+        let (dl, dr) = std::mem::replace(&mut self.delay_line[self.delay_idx], (l, r));
+        self.delay_idx = (self.delay_idx + 1) % self.delay_line.len();
+
+        // The true-peak estimate is conservative but not exact, so keep a
+        // hard safety net at the ceiling.
+        let out_l = (dl * gain).clamp(-self.ceiling, self.ceiling);
+        let out_r = (dr * gain).clamp(-self.ceiling, self.ceiling);
+        (out_l, out_r)
+    }
+}
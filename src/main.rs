@@ -1,23 +1,45 @@
 #![allow(unused_imports, dead_code)]
 
 use anyhow::Result;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use util::log_if_error;
 
 use cpal::traits::*;
 use cpal::SampleFormat;
 use structopt::StructOpt;
 
+mod arpeggiator;
 mod audio;
+mod button_map;
+mod clock;
+mod dither;
+mod limiter;
 mod midi;
 mod midi_controller;
+mod midi_effects;
+mod midi_learn;
+#[macro_use]
+mod params;
+mod rng;
 mod synth;
 mod synth_controller;
 mod synthesizers;
+mod udp;
 mod util;
+mod wav;
 
+use arpeggiator::{ArpConfig, Arpeggiator, StepTiming};
+use button_map::ButtonMap;
 use midi_controller::MidiController;
-use synth_controller::SynthController;
+use midi_effects::{ChordMemory, MidiEffect, MidiEffectsPart, NoteRepeat, Sequencer};
+use midi_learn::MidiLearn;
+use synth::Synth;
+use synth_controller::{Part, SynthController, SynthEvent};
+use synthesizers::EngineConfig;
+
+const GAIN_REDUCTION_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(StructOpt, Debug)]
 struct PlayOpt {
@@ -33,12 +55,124 @@ struct PlayOpt {
     /// The audio output devices.
     output_devices: Vec<String>,
 
-    /// Input midi ports.
+    #[structopt(long = "engine", default_value = "default")]
+    /// The synthesizer engine to play, see list-engines.
+    engine: String,
+
+    #[structopt(short = "e", long = "engine-option", number_of_values = 1)]
+    /// Engine-specific options of the form name=value, see list-engines.
+    engine_options: Vec<String>,
+
+    #[structopt(long = "button-map", default_value = "buttonmaps/bcr2000.toml")]
+    /// The TOML file mapping MIDI controllers to parameters.
+    button_map: String,
+
+    #[structopt(long = "midi-learn")]
+    /// Assign controllers by typing `learn <param>` and moving a control. The
+    /// assignment is written back to the button map file.
+    midi_learn: bool,
+
+    #[structopt(short = "d", long = "drum-channel")]
+    /// The MIDI channel on which General MIDI drum notes play the drum synth,
+    /// alongside the instrument on the keyboard channel.
+    drum_channel: Option<u8>,
+
+    #[structopt(long = "noise-shaping")]
+    /// Noise shape the dither when outputting to integer sample formats.
+    noise_shaping: bool,
+
+    #[structopt(short = "f", long = "feedback-port", number_of_values = 1)]
+    /// MIDI output ports to send parameter values back to, for controllers
+    /// with LED rings or motor faders.
+    feedback_midi_ports: Vec<String>,
+
+    #[structopt(
+        long = "port-match",
+        default_value = "exact",
+        possible_values = &["exact", "substring", "regex"]
+    )]
+    /// How input midi ports are matched against port names.
+    port_match: midi::PortMatch,
+
+    #[structopt(long = "virtual-port")]
+    /// Create a MIDI input port named "synth" that other programs can connect
+    /// to (Linux and macOS).
+    virtual_port: bool,
+
+    #[structopt(long = "udp")]
+    /// Listen for OSC and raw MIDI on this UDP address, e.g. 0.0.0.0:9000.
+    udp_address: Option<String>,
+
+    #[structopt(
+        long = "arp",
+        possible_values = &["up", "down", "up-down", "random", "as-played"],
+        conflicts_with_all = &["sequence", "note-repeat"]
+    )]
+    /// Arpeggiate the notes held on the keyboard channel in this pattern.
+    arp_pattern: Option<arpeggiator::Pattern>,
+
+    #[structopt(long = "arp-octaves", default_value = "1")]
+    /// The number of octaves the arpeggio spans, 1 to 4.
+    arp_octaves: u8,
+
+    #[structopt(
+        long = "arp-rate",
+        default_value = "1/16",
+        possible_values = &["1/4", "1/8", "1/8t", "1/16", "1/16t", "1/32"]
+    )]
+    /// The note length of the arpeggio, also the step length of the sequencer
+    /// and note repeat.
+    arp_rate: arpeggiator::Rate,
+
+    #[structopt(long = "arp-gate", default_value = "0.5")]
+    /// How long arpeggiated notes sound, as a fraction of their step.
+    arp_gate: f32,
+
+    #[structopt(long = "arp-swing", default_value = "0")]
+    /// Delay every second step of the arpeggio, from 0 (straight) to 1.
+    arp_swing: f32,
+
+    #[structopt(long = "arp-tempo", default_value = "120")]
+    /// The tempo of the arpeggio in beats per minute, unless synced.
+    arp_tempo: f32,
+
+    #[structopt(long = "arp-sync")]
+    /// Follow the tempo and position of incoming MIDI clock while it runs.
+    arp_sync: bool,
+
+    #[structopt(long = "chord")]
+    /// Chord memory: every key plays this chord, given as semitones above the
+    /// key such as 0,4,7.
+    chord: Option<midi_effects::Chord>,
+
+    #[structopt(long = "chord-capture-key")]
+    /// Chord memory: hold this MIDI note and play a chord to store it, every
+    /// other key then plays it. The key itself makes no sound.
+    chord_capture_key: Option<u8>,
+
+    #[structopt(long = "sequence", conflicts_with = "note-repeat")]
+    /// Play a sequence of up to 16 steps transposed by the last held key,
+    /// timed by the --arp-* options. Steps are separated by commas, each a
+    /// semitone offset with an optional MIDI velocity like 7:100, or - for a
+    /// rest.
+    sequence: Option<midi_effects::Sequence>,
+
+    #[structopt(long = "note-repeat")]
+    /// Retrigger the held notes on every step, timed by the --arp-* options.
+    note_repeat: bool,
+
+    #[structopt(long = "all-inputs")]
+    /// Connect to every MIDI input port, including ones plugged in later.
+    all_inputs: bool,
+
+    /// Input midi ports. Ports that are missing or unplugged are connected
+    /// when they appear.
     input_midi_ports: Vec<String>,
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(about = "Tools for working with midi.")]
+#[allow(clippy::large_enum_variant)] // Parsed once at startup.
 enum SynthOpt {
     /// List all available MIDI ports.
     ListMIDI,
@@ -46,12 +180,29 @@ enum SynthOpt {
     /// List all available audio devices.
     ListAudio,
 
+    /// List all synthesizer engines with their options and parameters.
+    ListEngines,
+
     /// Play the software synth.
     Play(PlayOpt),
 }
 
+fn start_stream(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sample_format: SampleFormat,
+    parts: Vec<Box<dyn Part>>,
+    noise_shaping: bool,
+) -> Result<(cpal::Stream, Arc<AtomicBool>)> {
+    match sample_format {
+        SampleFormat::F32 => audio::run::<f32>(device, config, parts, noise_shaping),
+        SampleFormat::I16 => audio::run::<i16>(device, config, parts, noise_shaping),
+        SampleFormat::U16 => audio::run::<u16>(device, config, parts, noise_shaping),
+    }
+}
+
 fn play(opt: PlayOpt) -> Result<()> {
-    // let button_map = ButtonMap::from_toml()?;
+    let mut button_map = ButtonMap::from_toml(&opt.button_map)?;
     let host = cpal::default_host();
 
     println!("{:?}", opt);
@@ -89,9 +240,42 @@ fn play(opt: PlayOpt) -> Result<()> {
             .collect()
     };
 
+    let engine = synthesizers::find_engine(&opt.engine)?;
+
+    let (midi_event_queue, mut port_watcher) =
+        midi::PortWatcher::new(&opt.input_midi_ports, opt.port_match, opt.all_inputs)?;
+    if opt.virtual_port {
+        port_watcher.create_virtual("synth")?;
+    }
 
-    let (midi_event_queue, _midi_connections) =
-        midi::connect_to_ports(opt.input_midi_ports.clone())?;
+    let timing = StepTiming {
+        rate: opt.arp_rate,
+        gate: opt.arp_gate,
+        swing: opt.arp_swing,
+        tempo: opt.arp_tempo,
+        sync: opt.arp_sync,
+    };
+    let effects_chain = |sample_rate| {
+        let mut effects: Vec<Box<dyn MidiEffect>> = vec![];
+        if opt.chord.is_some() || opt.chord_capture_key.is_some() {
+            let chord = opt.chord.clone();
+            effects.push(Box::new(ChordMemory::new(chord, opt.chord_capture_key)));
+        }
+        if let Some(pattern) = opt.arp_pattern {
+            let arp_config = ArpConfig {
+                pattern,
+                octaves: opt.arp_octaves,
+                timing,
+            };
+            effects.push(Box::new(Arpeggiator::new(arp_config, sample_rate)));
+        } else if let Some(sequence) = &opt.sequence {
+            let sequencer = Sequencer::new(sequence.clone(), timing, sample_rate);
+            effects.push(Box::new(sequencer));
+        } else if opt.note_repeat {
+            effects.push(Box::new(NoteRepeat::new(timing, sample_rate)));
+        }
+        effects
+    };
 
     let mut output_streams: Vec<_> = output_devices
         .into_iter()
@@ -108,35 +292,182 @@ fn play(opt: PlayOpt) -> Result<()> {
             let sample_format = supported_config.sample_format();
             let config: cpal::StreamConfig = supported_config.into();
 
-            let synth = synthesizers::default::DefaultSynth::new(
-                "buttonmaps/bcr2000.toml",
-                config.sample_rate.0 as f32,
-            )
-            .expect("could not create synth");
-
             let (kb_event_sender, kb_event_queue) = mpsc::sync_channel(1024);
             let kb_ctrlr = MidiController::new(
                 kb_event_sender,
                 opt.midi_keyboard_channel,
                 opt.midi_controller_channel,
+                button_map.resolve(engine.params),
             );
-            let synth_ctrlr = SynthController::new(kb_event_queue);
 
-            let stream = match sample_format {
-                SampleFormat::F32 => audio::run::<f32, _>(&device, &config, synth, synth_ctrlr),
-                SampleFormat::I16 => audio::run::<i16, _>(&device, &config, synth, synth_ctrlr),
-                SampleFormat::U16 => audio::run::<u16, _>(&device, &config, synth, synth_ctrlr),
+            let sample_rate = config.sample_rate.0 as f32;
+            let engine_config = EngineConfig::new(sample_rate, &opt.engine_options)
+                .expect("invalid engine options");
+            let effects = effects_chain(sample_rate);
+            let instrument = if effects.is_empty() {
+                engine
+                    .start(&engine_config, kb_event_queue)
+                    .expect("could not create synth")
+            } else {
+                let (fx_event_sender, fx_event_queue) = mpsc::sync_channel(1024);
+                let synth = engine
+                    .start(&engine_config, fx_event_queue)
+                    .expect("could not create synth");
+                Box::new(MidiEffectsPart::new(
+                    effects,
+                    kb_event_queue,
+                    fx_event_sender,
+                    synth,
+                ))
+            };
+
+            let mut midi_ctrlrs = vec![kb_ctrlr];
+            let mut parts = vec![instrument];
+            if let Some(drum_channel) = opt.drum_channel {
+                let (drum_event_sender, drum_event_queue) = mpsc::sync_channel(1024);
+                midi_ctrlrs.push(MidiController::new(
+                    drum_event_sender,
+                    drum_channel,
+                    opt.midi_controller_channel,
+                    button_map.resolve(synthesizers::drums::ENGINE.params),
+                ));
+                let drums_config = EngineConfig::new(sample_rate, &[]).unwrap();
+                let drums = synthesizers::drums::ENGINE
+                    .start(&drums_config, drum_event_queue)
+                    .expect("could not create drum synth");
+                parts.push(drums);
             }
-            .unwrap();
 
-            (kb_ctrlr, stream)
+            let meters: Vec<_> = parts
+                .iter()
+                .filter_map(|part| part.gain_reduction_meter())
+                .collect();
+            let missing_programs: Vec<_> = parts
+                .iter()
+                .filter_map(|part| part.missing_program())
+                .collect();
+            let (stream, clip_indicator) =
+                start_stream(&device, &config, sample_format, parts, opt.noise_shaping).unwrap();
+
+            (
+                midi_ctrlrs,
+                stream,
+                clip_indicator,
+                meters,
+                missing_programs,
+            )
         })
         .collect();
 
+    let mut feedback_outputs = opt
+        .feedback_midi_ports
+        .iter()
+        .map(|port_name| midi::Output::connect(port_name))
+        .collect::<Result<Vec<_>>>()?;
+    for output_stream in output_streams.iter_mut() {
+        for midi_ctrlr in output_stream.0.iter_mut() {
+            midi_ctrlr.send_all_feedback();
+        }
+    }
+
+    let mut param_names: Vec<_> = engine.params.iter().map(|p| p.name).collect();
+    if opt.drum_channel.is_some() {
+        for param in synthesizers::drums::ENGINE.params {
+            if !param_names.contains(&param.name) {
+                param_names.push(param.name);
+            }
+        }
+    }
+
+    if let Some(address) = &opt.udp_address {
+        let routing = udp::Routing {
+            keyboard_channel: opt.midi_keyboard_channel,
+            controller_channel: opt.midi_controller_channel,
+            param_names: param_names.clone(),
+        };
+        udp::listen(address, routing, port_watcher.sender())?;
+    }
+
+    let mut midi_learn = if opt.midi_learn {
+        Some(MidiLearn::new(opt.midi_controller_channel, param_names))
+    } else {
+        None
+    };
+
+    let mut last_meter_report = Instant::now();
     loop {
-        let event = midi_event_queue.recv().unwrap();
+        port_watcher.poll()?;
+        if let Some(midi_learn) = midi_learn.as_mut() {
+            midi_learn.poll_commands();
+        }
+
+        match midi_event_queue.recv_timeout(Duration::from_millis(100)) {
+            Ok(event) => {
+                let learned = midi_learn
+                    .as_mut()
+                    .and_then(|midi_learn| midi_learn.handle_midi_event(&event));
+                if let Some((name, controller)) = learned {
+                    if let Err(err) = button_map.assign(name, controller) {
+                        eprintln!("saving the button map failed: {}", err);
+                    }
+                    println!("assigned controller {} to {}", controller, name);
+                    for other in button_map.names_for(controller).filter(|n| *n != name) {
+                        eprintln!(
+                            "warning: controller {} is also assigned to {}",
+                            controller, other
+                        );
+                    }
+                    for output_stream in output_streams.iter_mut() {
+                        for midi_ctrlr in output_stream.0.iter_mut() {
+                            midi_ctrlr.update_button_map(&button_map);
+                        }
+                    }
+                } else {
+                    for output_stream in output_streams.iter_mut() {
+                        for midi_ctrlr in output_stream.0.iter_mut() {
+                            midi_ctrlr.handle_midi_event(event);
+                        }
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(err) => return Err(err.into()),
+        }
+
         for output_stream in output_streams.iter_mut() {
-            output_stream.0.handle_midi_event(event);
+            for midi_ctrlr in output_stream.0.iter_mut() {
+                for event in midi_ctrlr.take_feedback() {
+                    for output in feedback_outputs.iter_mut() {
+                        if let Err(err) = output.send(&event) {
+                            eprintln!("sending MIDI feedback failed: {}", err);
+                        }
+                    }
+                }
+            }
+        }
+
+        for output_stream in output_streams.iter() {
+            if output_stream.2.swap(false, Ordering::Relaxed) {
+                eprintln!("clip: synth output exceeded 0 dBFS, limiter engaged");
+            }
+            for missing_program in output_stream.4.iter() {
+                match missing_program.swap(0, Ordering::Relaxed) {
+                    0 => {}
+                    program => eprintln!("no preset for program {}", program - 1),
+                }
+            }
+        }
+
+        if last_meter_report.elapsed() >= GAIN_REDUCTION_REPORT_INTERVAL {
+            last_meter_report = Instant::now();
+            for output_stream in output_streams.iter() {
+                for meter in output_stream.3.iter() {
+                    let reduction = f32::from_bits(meter.swap(0, Ordering::Relaxed));
+                    if reduction >= 0.1 {
+                        println!("compressor: {:.1} dB gain reduction", reduction);
+                    }
+                }
+            }
         }
     }
 }
@@ -173,6 +504,24 @@ fn main() -> Result<()> {
             }
         }
 
+        SynthOpt::ListEngines => {
+            for engine in synthesizers::ENGINES {
+                println!("{}: {}", engine.name, engine.description);
+                for option in engine.options {
+                    println!("    -e {}=...  {}", option.name, option.help);
+                }
+                for param in engine.params {
+                    println!(
+                        "    {}: {} to {}, default {}",
+                        param.name,
+                        param.format(param.min),
+                        param.format(param.max),
+                        param.format(param.default)
+                    );
+                }
+            }
+        }
+
         SynthOpt::Play(playopt) => {
             return play(playopt);
         }
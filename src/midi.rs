@@ -1,15 +1,30 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use midir::MidiInputConnection;
-use midly::live::LiveEvent;
+use midir::{MidiInputConnection, MidiOutputConnection};
+use midly::live::{LiveEvent, SystemCommon, SystemRealtime};
 use midly::MidiMessage;
+use regex::Regex;
 
 #[derive(Copy, Clone, Debug)]
 pub enum EventContent {
     NoteOff { key: u8, vel: u8 },
     NoteOn { key: u8, vel: u8 },
     Controller { controller: u8, value: u8 },
+    ProgramChange { program: u8 },
+    // 14-bit, centered at 8192.
+    PitchBend { value: u16 },
+    // Not MIDI, sets the position of a parameter in [0, 1].
+    Param { name: &'static str, value: f32 },
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    // In sixteenth notes.
+    SongPosition { position: u16 },
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -33,77 +48,320 @@ pub fn list_devices() -> Result<Vec<String>> {
 
 pub struct Connection(MidiInputConnection<mpsc::SyncSender<Event>>);
 
-pub fn connect_to_ports(
-    midi_ports: Vec<String>,
-) -> Result<(mpsc::Receiver<Event>, Vec<Connection>)> {
-    let (sender, receiver) = mpsc::sync_channel(1024);
+/// How input port names given on the command line select ports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PortMatch {
+    Exact,
+    Substring,
+    Regex,
+}
+
+impl FromStr for PortMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exact" => Ok(PortMatch::Exact),
+            "substring" => Ok(PortMatch::Substring),
+            "regex" => Ok(PortMatch::Regex),
+            _ => Err(anyhow!("unknown port match {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PortPattern {
+    Exact(String),
+    Substring(String),
+    Regex(Regex),
+}
+
+impl PortPattern {
+    fn new(pattern: &str, port_match: PortMatch) -> Result<Self> {
+        Ok(match port_match {
+            PortMatch::Exact => PortPattern::Exact(pattern.to_owned()),
+            PortMatch::Substring => PortPattern::Substring(pattern.to_owned()),
+            PortMatch::Regex => PortPattern::Regex(Regex::new(pattern)?),
+        })
+    }
+
+    fn matches(&self, port_name: &str) -> bool {
+        match self {
+            PortPattern::Exact(name) => port_name == name,
+            PortPattern::Substring(s) => port_name.contains(s.as_str()),
+            PortPattern::Regex(re) => re.is_match(port_name),
+        }
+    }
+}
+
+const PORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the synth connected to the matching input ports, connecting to
+/// ports as they appear and dropping them when their device goes away.
+pub struct PortWatcher {
+    patterns: Vec<PortPattern>,
+    all_inputs: bool,
+    sender: mpsc::SyncSender<Event>,
+    connections: HashMap<String, Connection>,
+    virtual_port: Option<Connection>,
+    last_poll: Option<Instant>,
+}
+
+impl PortWatcher {
+    /// Connects to the ports available now. With `all_inputs` every input
+    /// port is used and the patterns are ignored.
+    pub fn new(
+        patterns_text: &[String],
+        port_match: PortMatch,
+        all_inputs: bool,
+    ) -> Result<(mpsc::Receiver<Event>, Self)> {
+        let (sender, receiver) = mpsc::sync_channel(1024);
+        let patterns = patterns_text
+            .iter()
+            .map(|p| PortPattern::new(p, port_match))
+            .collect::<Result<_>>()?;
+        let mut watcher = Self {
+            patterns,
+            all_inputs,
+            sender,
+            connections: HashMap::new(),
+            virtual_port: None,
+            last_poll: None,
+        };
+        watcher.poll()?;
 
-    let connections: Result<Vec<Connection>> = midi_ports
+        for (text, pattern) in patterns_text.iter().zip(&watcher.patterns) {
+            if !watcher.connections.keys().any(|name| pattern.matches(name)) {
+                eprintln!("waiting for MIDI port {}", text);
+            }
+        }
+        Ok((receiver, watcher))
+    }
+
+    /// A sender into the event queue, for inputs other than MIDI ports.
+    pub fn sender(&self) -> mpsc::SyncSender<Event> {
+        self.sender.clone()
+    }
+
+    /// Creates an input port of our own that sequencers and DAWs on the same
+    /// machine can connect to.
+    #[cfg(unix)]
+    pub fn create_virtual(&mut self, name: &str) -> Result<()> {
+        use midir::os::unix::VirtualInput;
+
+        let midi_in = midir::MidiInput::new(name)?;
+        let conn = midi_in
+            .create_virtual(name, handle_message, self.sender.clone())
+            .map_err(|err| anyhow!("could not create virtual MIDI port {}: {}", name, err))?;
+        self.virtual_port = Some(Connection(conn));
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn create_virtual(&mut self, _name: &str) -> Result<()> {
+        bail!("virtual MIDI ports are not supported on this platform")
+    }
+
+    /// Looks for ports that appeared or disappeared. Cheap to call often, the
+    /// ports are only listed once per `PORT_POLL_INTERVAL`.
+    pub fn poll(&mut self) -> Result<()> {
+        if self
+            .last_poll
+            .is_some_and(|t| t.elapsed() < PORT_POLL_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_poll = Some(Instant::now());
+
+        let midi_in = midir::MidiInput::new("synth port watcher")?;
+        let port_names: Vec<String> = midi_in
+            .ports()
+            .iter()
+            .filter_map(|port| midi_in.port_name(port).ok())
+            .collect();
+
+        self.connections.retain(|name, _| {
+            let present = port_names.contains(name);
+            if !present {
+                eprintln!("MIDI port {} disconnected", name);
+            }
+            present
+        });
+
+        for name in port_names {
+            let wanted = self.all_inputs || self.patterns.iter().any(|p| p.matches(&name));
+            if !wanted || self.connections.contains_key(&name) {
+                continue;
+            }
+
+            match connect(&name, self.sender.clone()) {
+                Ok(conn) => {
+                    println!("connected to MIDI port {}", name);
+                    self.connections.insert(name, conn);
+                }
+                Err(err) => eprintln!("could not connect to MIDI port {}: {}", name, err),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn connect(port_name: &str, sender: mpsc::SyncSender<Event>) -> Result<Connection> {
+    let midi_in = midir::MidiInput::new(&format!("synth to {}", port_name))?;
+
+    let selected_port = midi_in
+        .ports()
         .into_iter()
-        .map(|port_name| {
-            let midi_in = midir::MidiInput::new(&format!("synth to {}", port_name))?;
-
-            let selected_port = midi_in
-                .ports()
-                .into_iter()
-                .find(|p| midi_in.port_name(&p) == Ok(port_name.clone()))
-                .expect(&format!("could not find MIDI port {}", port_name));
-
-            let connect_result = midi_in.connect(
-                &selected_port,
-                &format!("synth conn to {}", port_name),
-                |timestamp, bytes, sender| {
-                    let midly_event = LiveEvent::parse(bytes);
-                    match midly_event {
-                        Ok(LiveEvent::Midi { channel, message }) => {
-                            let content = match message {
-                                MidiMessage::NoteOff { key, vel } => EventContent::NoteOff {
-                                    key: key.into(),
-                                    vel: vel.into(),
-                                },
-
-                                MidiMessage::NoteOn { key, vel } => EventContent::NoteOn {
-                                    key: key.into(),
-                                    vel: vel.into(),
-                                },
-
-                                MidiMessage::Controller { controller, value } => {
-                                    EventContent::Controller {
-                                        controller: controller.into(),
-                                        value: value.into(),
-                                    }
-                                }
-
-                                _ => return,
-                            };
-
-                            let send_result = sender.send(Event {
-                                timestamp,
-                                channel: channel.into(),
-                                content,
-                            });
-
-                            if let Err(err) = send_result {
-                                eprintln!("failed to send MIDI event, error: {:?}", err);
-                            }
-                        }
-
-                        Err(err) => {
-                            eprintln!("midly failed to parse {:?}, error: {:?}", bytes, err);
-                        }
-
-                        _ => {}
-                    }
+        .find(|p| midi_in.port_name(p).as_deref() == Ok(port_name))
+        .ok_or_else(|| anyhow!("could not find MIDI port {}", port_name))?;
+
+    let conn = midi_in
+        .connect(
+            &selected_port,
+            &format!("synth conn to {}", port_name),
+            handle_message,
+            sender,
+        )
+        .map_err(|err| anyhow!("{}", err))?;
+    Ok(Connection(conn))
+}
+
+/// Parses a MIDI message and queues the events we understand.
+pub fn handle_message(timestamp: u64, bytes: &[u8], sender: &mut mpsc::SyncSender<Event>) {
+    let midly_event = LiveEvent::parse(bytes);
+    let (channel, content) = match midly_event {
+        Ok(LiveEvent::Midi { channel, message }) => {
+            let content = match message {
+                MidiMessage::NoteOff { key, vel } => EventContent::NoteOff {
+                    key: key.into(),
+                    vel: vel.into(),
+                },
+
+                MidiMessage::NoteOn { key, vel } => EventContent::NoteOn {
+                    key: key.into(),
+                    vel: vel.into(),
                 },
-                sender.clone(),
-            );
 
-            match connect_result {
-                Ok(conn) => Ok(Connection(conn)),
-                Err(err) => Err(err.into()),
+                MidiMessage::Controller { controller, value } => EventContent::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+
+                MidiMessage::ProgramChange { program } => EventContent::ProgramChange {
+                    program: program.into(),
+                },
+
+                MidiMessage::PitchBend { bend } => EventContent::PitchBend {
+                    value: bend.0.into(),
+                },
+
+                _ => return,
+            };
+            (channel.into(), content)
+        }
+
+        // System messages have no channel, they go out as channel 0.
+        Ok(LiveEvent::Realtime(message)) => {
+            let content = match message {
+                SystemRealtime::TimingClock => EventContent::Clock,
+                SystemRealtime::Start => EventContent::Start,
+                SystemRealtime::Continue => EventContent::Continue,
+                SystemRealtime::Stop => EventContent::Stop,
+                _ => return,
+            };
+            (0, content)
+        }
+
+        Ok(LiveEvent::Common(SystemCommon::SongPosition(position))) => (
+            0,
+            EventContent::SongPosition {
+                position: position.into(),
+            },
+        ),
+
+        Err(err) => {
+            eprintln!("midly failed to parse {:?}, error: {:?}", bytes, err);
+            return;
+        }
+
+        _ => return,
+    };
+
+    let send_result = sender.send(Event {
+        timestamp,
+        channel,
+        content,
+    });
+
+    if let Err(err) = send_result {
+        eprintln!("failed to send MIDI event, error: {:?}", err);
+    }
+}
+
+/// A connection for sending MIDI, such as feedback to a controller.
+pub struct Output(MidiOutputConnection);
+
+impl Output {
+    pub fn connect(port_name: &str) -> Result<Self> {
+        let midi_out = midir::MidiOutput::new(&format!("synth to {}", port_name))?;
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|p| midi_out.port_name(p).as_deref() == Ok(port_name))
+            .ok_or_else(|| anyhow!("could not find MIDI output port {}", port_name))?;
+        let conn = midi_out
+            .connect(&port, &format!("synth conn to {}", port_name))
+            .map_err(|err| anyhow!("could not connect to MIDI output {}: {}", port_name, err))?;
+        Ok(Self(conn))
+    }
+
+    pub fn send(&mut self, event: &Event) -> Result<()> {
+        let message = match event.content {
+            EventContent::NoteOff { key, vel } => MidiMessage::NoteOff {
+                key: key.into(),
+                vel: vel.into(),
+            },
+            EventContent::NoteOn { key, vel } => MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+            EventContent::Controller { controller, value } => MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+            EventContent::ProgramChange { program } => MidiMessage::ProgramChange {
+                program: program.into(),
+            },
+            EventContent::PitchBend { value } => MidiMessage::PitchBend {
+                bend: midly::PitchBend(value.into()),
+            },
+            EventContent::Clock => {
+                return self.send_live(LiveEvent::Realtime(SystemRealtime::TimingClock))
+            }
+            EventContent::Start => {
+                return self.send_live(LiveEvent::Realtime(SystemRealtime::Start))
             }
+            EventContent::Continue => {
+                return self.send_live(LiveEvent::Realtime(SystemRealtime::Continue))
+            }
+            EventContent::Stop => return self.send_live(LiveEvent::Realtime(SystemRealtime::Stop)),
+            EventContent::SongPosition { position } => {
+                let message = SystemCommon::SongPosition(position.into());
+                return self.send_live(LiveEvent::Common(message));
+            }
+            EventContent::Param { .. } => return Ok(()),
+        };
+
+        self.send_live(LiveEvent::Midi {
+            channel: event.channel.into(),
+            message,
         })
-        .collect();
+    }
 
-    connections.map(|conn| (receiver, conn))
+    fn send_live(&mut self, event: LiveEvent) -> Result<()> {
+        let mut bytes = Vec::with_capacity(3);
+        event.write_std(&mut bytes)?;
+        self.0.send(&bytes)?;
+        Ok(())
+    }
 }
@@ -1,10 +1,95 @@
 use std::sync::mpsc;
 
+use crate::button_map::{Binding, ButtonMap, Mode, ParamMap, Source};
+use crate::clock::MidiClock;
 use crate::midi;
+use crate::params::ParamId;
 use crate::synth_controller::SynthEvent;
 use crate::util::*;
 
 const MIDI_SUSTAIN_PEDAL: u8 = 64;
+const MIDI_DATA_ENTRY_MSB: u8 = 6;
+const MIDI_DATA_ENTRY_LSB: u8 = 38;
+const MIDI_NRPN_LSB: u8 = 98;
+const MIDI_NRPN_MSB: u8 = 99;
+const MIDI_RPN_LSB: u8 = 100;
+const MIDI_RPN_MSB: u8 = 101;
+
+const RPN_PITCH_BEND_RANGE: u16 = 0;
+const RPN_NULL: u16 = 0x3fff;
+const DEFAULT_PITCH_BEND_RANGE: f32 = 2.0;
+
+/// A registered or non-registered parameter number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ParameterNumber {
+    Rpn(u16),
+    Nrpn(u16),
+}
+
+/// Follows the (N)RPN selected on a channel and the value entered for it.
+#[derive(Clone, Debug)]
+struct DataEntry {
+    registered: bool,
+    number_msb: u8,
+    number_lsb: u8,
+    value: u16,
+}
+
+impl DataEntry {
+    fn new() -> Self {
+        Self {
+            registered: true,
+            number_msb: 0x7f,
+            number_lsb: 0x7f,
+            value: 0,
+        }
+    }
+
+    fn selected(&self) -> Option<ParameterNumber> {
+        let number = (self.number_msb as u16) << 7 | self.number_lsb as u16;
+        match (self.registered, number) {
+            (true, RPN_NULL) => None,
+            (true, n) => Some(ParameterNumber::Rpn(n)),
+            (false, n) => Some(ParameterNumber::Nrpn(n)),
+        }
+    }
+
+    fn select(&mut self, registered: bool) {
+        if self.registered != registered {
+            self.registered = registered;
+            self.number_msb = 0;
+            self.number_lsb = 0;
+        }
+        self.value = 0;
+    }
+
+    /// Returns the selected parameter and its 14-bit value if the controller
+    /// entered data.
+    fn handle(&mut self, controller: u8, value: u8) -> Option<(ParameterNumber, u16)> {
+        match controller {
+            MIDI_NRPN_MSB | MIDI_RPN_MSB => {
+                self.select(controller == MIDI_RPN_MSB);
+                self.number_msb = value;
+                None
+            }
+            MIDI_NRPN_LSB | MIDI_RPN_LSB => {
+                self.select(controller == MIDI_RPN_LSB);
+                self.number_lsb = value;
+                None
+            }
+            // A new MSB resets the LSB, as with 14-bit controllers.
+            MIDI_DATA_ENTRY_MSB => {
+                self.value = (value as u16) << 7;
+                Some((self.selected()?, self.value))
+            }
+            MIDI_DATA_ENTRY_LSB => {
+                self.value = (self.value & !0x7f) | value as u16;
+                Some((self.selected()?, self.value))
+            }
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct MidiController {
@@ -14,6 +99,19 @@ pub struct MidiController {
     event_output: mpsc::SyncSender<SynthEvent>,
     keyboard_channel: u8,
     controller_channel: u8,
+    param_map: ParamMap,
+    // Normalized position of each parameter, where relative controls and
+    // toggles move from.
+    positions: Vec<f32>,
+    // The last MSB of each 14-bit controller pair.
+    controller_msb: [u8; 32],
+    keyboard_data_entry: DataEntry,
+    controller_data_entry: DataEntry,
+    // In [-1, 1], and the range in semitones set through RPN 0.
+    pitch_bend: f32,
+    pitch_bend_range: f32,
+    feedback: Vec<midi::Event>,
+    clock: MidiClock,
 }
 
 impl MidiController {
@@ -21,6 +119,7 @@ impl MidiController {
         event_output: mpsc::SyncSender<SynthEvent>,
         keyboard_channel: u8,
         controller_channel: u8,
+        param_map: ParamMap,
     ) -> Self {
         Self {
             sustain_pedal: false,
@@ -29,6 +128,39 @@ impl MidiController {
             event_output,
             keyboard_channel,
             controller_channel,
+            positions: param_map
+                .params()
+                .iter()
+                .map(|info| info.normalize(info.default))
+                .collect(),
+            param_map,
+            controller_msb: [0; 32],
+            keyboard_data_entry: DataEntry::new(),
+            controller_data_entry: DataEntry::new(),
+            pitch_bend: 0.0,
+            pitch_bend_range: DEFAULT_PITCH_BEND_RANGE,
+            feedback: Vec::new(),
+            clock: MidiClock::new(),
+        }
+    }
+
+    /// Picks up changed controller assignments.
+    pub fn update_button_map(&mut self, button_map: &ButtonMap) {
+        self.param_map = button_map.resolve(self.param_map.params());
+        self.send_all_feedback();
+    }
+
+    /// Controller messages reflecting parameter values, for the LED rings
+    /// and motor faders of the controller. Taken after handling each event.
+    pub fn take_feedback(&mut self) -> Vec<midi::Event> {
+        std::mem::take(&mut self.feedback)
+    }
+
+    /// Queues feedback for all assigned parameters, so the controller shows
+    /// the sound that is loaded.
+    pub fn send_all_feedback(&mut self) {
+        for idx in 0..self.positions.len() {
+            self.queue_feedback(ParamId(idx));
         }
     }
 
@@ -100,15 +232,189 @@ impl MidiController {
                 }
 
                 if event.channel == self.controller_channel {
-                    self.send_event(SynthEvent::ParamChange {
-                        param: controller,
-                        value: value as f32 / 127.0,
-                    });
+                    self.handle_controller(controller, value);
+                } else if event.channel == self.keyboard_channel {
+                    if let Some(entry) = self.keyboard_data_entry.handle(controller, value) {
+                        self.handle_data_entry(entry);
+                    }
+                }
+            }
+
+            midi::EventContent::Param { name, value } => {
+                if event.channel == self.controller_channel {
+                    self.set_param_by_name(name, value);
+                }
+            }
+
+            midi::EventContent::Clock
+            | midi::EventContent::Start
+            | midi::EventContent::Continue
+            | midi::EventContent::Stop
+            | midi::EventContent::SongPosition { .. } => {
+                self.clock.handle_midi_event(&event);
+                self.send_event(SynthEvent::Transport {
+                    transport: self.clock.transport(),
+                });
+            }
+
+            midi::EventContent::PitchBend { value } => {
+                if event.channel == self.keyboard_channel {
+                    self.pitch_bend = ((value as f32 - 8192.0) / 8191.0).clamp(-1.0, 1.0);
+                    self.send_pitch_bend();
+                }
+            }
+
+            midi::EventContent::ProgramChange { program } => {
+                if event.channel == self.keyboard_channel {
+                    self.send_event(SynthEvent::ProgramChange { program });
+                    self.send_all_feedback();
                 }
             }
         }
     }
 
+    /// Handles a controller on the controller channel. Assignments take
+    /// precedence, so the data entry controllers are free for other uses in
+    /// button maps without NRPNs.
+    fn handle_controller(&mut self, controller: u8, value: u8) {
+        if let Some(binding) = self.param_map.lookup(controller) {
+            self.apply_binding(binding, value as u16, 127);
+        } else if let Some(binding) = self.param_map.lookup14(controller) {
+            self.controller_msb[controller as usize] = value;
+            // Steps and button presses only come in on the MSB.
+            if binding.mode == Mode::Absolute {
+                self.apply_binding(binding, (value as u16) << 7, 16383);
+            } else {
+                self.apply_binding(binding, value as u16, 127);
+            }
+        } else if let Some(binding) = controller
+            .checked_sub(32)
+            .and_then(|msb_controller| self.param_map.lookup14(msb_controller))
+        {
+            if binding.mode == Mode::Absolute {
+                let msb = self.controller_msb[controller as usize - 32];
+                self.apply_binding(binding, (msb as u16) << 7 | value as u16, 16383);
+            }
+        } else if let Some(entry) = self.controller_data_entry.handle(controller, value) {
+            self.handle_data_entry(entry);
+        }
+    }
+
+    fn handle_data_entry(&mut self, (number, value): (ParameterNumber, u16)) {
+        match number {
+            ParameterNumber::Rpn(RPN_PITCH_BEND_RANGE) => {
+                // Semitones in the MSB, cents in the LSB.
+                self.pitch_bend_range = (value >> 7) as f32 + (value & 0x7f).min(99) as f32 / 100.0;
+                self.send_pitch_bend();
+            }
+            ParameterNumber::Nrpn(nrpn) => {
+                if let Some(binding) = self.param_map.lookup_nrpn(nrpn) {
+                    self.apply_binding(binding, value, 16383);
+                }
+            }
+            ParameterNumber::Rpn(_) => {}
+        }
+    }
+
+    /// Moves a parameter according to a controller value between 0 and
+    /// `resolution`.
+    fn apply_binding(&mut self, binding: Binding, value: u16, resolution: u16) {
+        let position = self.positions[binding.param.0];
+        let new_position = match binding.mode {
+            Mode::Absolute => {
+                let x = value as f32 / resolution as f32;
+                binding.low + x * (binding.high - binding.low)
+            }
+            Mode::Toggle if value == 0 => return,
+            Mode::Toggle => {
+                // Go to whichever end is further away.
+                if (position - binding.high).abs() < (position - binding.low).abs() {
+                    binding.low
+                } else {
+                    binding.high
+                }
+            }
+            Mode::Momentary if value > 0 => binding.high,
+            Mode::Momentary => binding.low,
+            mode => {
+                let steps = mode.relative_steps(value, resolution).unwrap_or(0);
+                let delta = steps as f32 / resolution as f32 * (binding.high - binding.low);
+                let (min, max) = (binding.low.min(binding.high), binding.low.max(binding.high));
+                (position + delta).clamp(min, max)
+            }
+        };
+
+        self.positions[binding.param.0] = new_position;
+        self.send_event(SynthEvent::ParamChange {
+            param: binding.param,
+            value: binding.info.denormalize(new_position),
+        });
+
+        // Absolute controls already show where they are.
+        if binding.mode != Mode::Absolute {
+            self.queue_feedback(binding.param);
+        }
+    }
+
+    fn set_param_by_name(&mut self, name: &str, position: f32) {
+        let params = self.param_map.params();
+        if let Some(idx) = params.iter().position(|info| info.name == name) {
+            self.positions[idx] = position.clamp(0.0, 1.0);
+            self.send_event(SynthEvent::ParamChange {
+                param: ParamId(idx),
+                value: params[idx].denormalize(self.positions[idx]),
+            });
+            self.queue_feedback(ParamId(idx));
+        }
+    }
+
+    fn queue_feedback(&mut self, param: ParamId) {
+        let (source, binding) = match self.param_map.binding_for(param) {
+            Some(b) => b,
+            None => return,
+        };
+        let span = binding.high - binding.low;
+        let x = if span != 0.0 {
+            ((self.positions[param.0] - binding.low) / span).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        let x = match binding.mode {
+            Mode::Toggle | Mode::Momentary => x.round(),
+            _ => x,
+        };
+
+        match source {
+            Source::Controller(cc) => self.push_feedback(cc, (x * 127.0).round() as u8),
+            Source::Controller14(cc) => {
+                let value = (x * 16383.0).round() as u16;
+                self.push_feedback(cc, (value >> 7) as u8);
+                self.push_feedback(cc + 32, (value & 0x7f) as u8);
+            }
+            Source::Nrpn(nrpn) => {
+                let value = (x * 16383.0).round() as u16;
+                self.push_feedback(MIDI_NRPN_MSB, (nrpn >> 7) as u8);
+                self.push_feedback(MIDI_NRPN_LSB, (nrpn & 0x7f) as u8);
+                self.push_feedback(MIDI_DATA_ENTRY_MSB, (value >> 7) as u8);
+                self.push_feedback(MIDI_DATA_ENTRY_LSB, (value & 0x7f) as u8);
+            }
+        }
+    }
+
+    fn push_feedback(&mut self, controller: u8, value: u8) {
+        self.feedback.push(midi::Event {
+            timestamp: 0,
+            channel: self.controller_channel,
+            content: midi::EventContent::Controller { controller, value },
+        });
+    }
+
+    fn send_pitch_bend(&mut self) {
+        self.send_event(SynthEvent::PitchBend {
+            semitones: self.pitch_bend * self.pitch_bend_range,
+        });
+    }
+
     fn send_event(&mut self, event: SynthEvent) {
         let r = self.event_output.try_send(event);
         log_if_error("note send_event failed", r);
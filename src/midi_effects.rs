@@ -0,0 +1,351 @@
+use anyhow::{anyhow, bail, Result};
+use std::str::FromStr;
+use std::sync::atomic::AtomicU32;
+use std::sync::{mpsc, Arc};
+
+use crate::arpeggiator::{Arpeggiator, StepTiming, Stepper};
+use crate::synth_controller::{Part, SynthEvent};
+use crate::util::*;
+
+/// The most steps a sequence can have.
+pub const MAX_SEQUENCE_STEPS: usize = 16;
+
+/// Transforms the events going to a synth, possibly playing notes of its own
+/// over time.
+pub trait MidiEffect: Send {
+    fn handle_event(&mut self, event: SynthEvent, emit: &mut dyn FnMut(SynthEvent));
+    /// Called once per sample.
+    fn step_frame(&mut self, _emit: &mut dyn FnMut(SynthEvent)) {}
+}
+
+impl MidiEffect for Arpeggiator {
+    fn handle_event(&mut self, event: SynthEvent, emit: &mut dyn FnMut(SynthEvent)) {
+        if let Some(event) = Arpeggiator::handle_event(self, event) {
+            emit(event);
+        }
+    }
+
+    fn step_frame(&mut self, emit: &mut dyn FnMut(SynthEvent)) {
+        Arpeggiator::step_frame(self, emit);
+    }
+}
+
+/// Semitones above the played key, such as `0,4,7` for a major chord.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chord(Vec<i8>);
+
+impl FromStr for Chord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let intervals = s
+            .split(',')
+            .map(|interval| {
+                interval
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("invalid chord interval {}", interval))
+            })
+            .collect::<Result<Vec<i8>>>()?;
+        Ok(Chord(intervals))
+    }
+}
+
+/// Plays a stored chord for every key. Holding the capture key while playing
+/// a chord stores it, relative to its lowest note. Until a chord is stored
+/// keys play as usual.
+pub struct ChordMemory {
+    intervals: Vec<i8>,
+    capture_key: Option<u8>,
+    capturing: bool,
+    captured: Vec<u8>,
+
+    // The notes each played key started, so a key releases what it played
+    // even if the chord changed since.
+    started: Vec<(u8, u8)>,
+    // How many played keys hold each note, so overlapping chords only
+    // release shared notes when the last key goes up.
+    holds: [u8; 128],
+}
+
+impl ChordMemory {
+    pub fn new(chord: Option<Chord>, capture_key: Option<u8>) -> Self {
+        // Room for any chord, so capturing doesn't allocate.
+        let mut intervals = Vec::with_capacity(128);
+        intervals.extend(chord.map_or(vec![], |chord| chord.0));
+        Self {
+            intervals,
+            capture_key,
+            capturing: false,
+            captured: Vec::with_capacity(128),
+            started: Vec::with_capacity(128 * 4),
+            holds: [0; 128],
+        }
+    }
+
+    fn note_on(&mut self, played: u8, key: u8, vel: f32, emit: &mut dyn FnMut(SynthEvent)) {
+        self.started.push((played, key));
+        let holds = &mut self.holds[key as usize];
+        *holds = holds.saturating_add(1);
+        // Another key already plays this note, retrigger it.
+        if *holds > 1 {
+            emit(SynthEvent::NoteOff { key });
+        }
+        emit(SynthEvent::NoteOn { key, vel });
+    }
+
+    fn store_captured(&mut self) {
+        self.captured.sort_unstable();
+        self.captured.dedup();
+        if let Some(&root) = self.captured.first() {
+            self.intervals.clear();
+            let intervals = self.captured.iter().map(|&key| (key - root) as i8);
+            self.intervals.extend(intervals);
+        }
+    }
+}
+
+impl MidiEffect for ChordMemory {
+    fn handle_event(&mut self, event: SynthEvent, emit: &mut dyn FnMut(SynthEvent)) {
+        match event {
+            SynthEvent::NoteOn { key, .. } if Some(key) == self.capture_key => {
+                self.capturing = true;
+                self.captured.clear();
+            }
+            SynthEvent::NoteOff { key } if Some(key) == self.capture_key => {
+                self.capturing = false;
+                self.store_captured();
+            }
+            // Play the chord being captured as is.
+            SynthEvent::NoteOn { key, vel } if self.capturing || self.intervals.is_empty() => {
+                if self.capturing {
+                    self.captured.push(key);
+                }
+                self.note_on(key, key, vel, emit);
+            }
+            SynthEvent::NoteOn { key: played, vel } => {
+                for i in 0..self.intervals.len() {
+                    let key = played as i32 + self.intervals[i] as i32;
+                    if (0..128).contains(&key) {
+                        self.note_on(played, key as u8, vel, emit);
+                    }
+                }
+            }
+            SynthEvent::NoteOff { key: played } => {
+                let holds = &mut self.holds;
+                self.started.retain(|&(p, key)| {
+                    if p != played {
+                        return true;
+                    }
+                    let count = &mut holds[key as usize];
+                    *count = count.saturating_sub(1);
+                    if *count == 0 {
+                        emit(SynthEvent::NoteOff { key });
+                    }
+                    false
+                });
+            }
+            event => emit(event),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SequenceStep {
+    /// Semitones above the held key.
+    pub offset: i8,
+    /// `None` plays the step with the velocity of the held key.
+    pub vel: Option<f32>,
+}
+
+/// Up to 16 steps separated by commas, each `offset`, `offset:velocity` with
+/// a MIDI velocity, or `-` for a rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence(Vec<Option<SequenceStep>>);
+
+impl FromStr for Sequence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let steps = s
+            .split(',')
+            .map(|step| parse_step(step.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        if steps.len() > MAX_SEQUENCE_STEPS {
+            bail!(
+                "sequence has {} steps, at most {} are allowed",
+                steps.len(),
+                MAX_SEQUENCE_STEPS
+            );
+        }
+        Ok(Sequence(steps))
+    }
+}
+
+fn parse_step(step: &str) -> Result<Option<SequenceStep>> {
+    if step == "-" {
+        return Ok(None);
+    }
+    let invalid = || anyhow!("invalid sequence step {}", step);
+    let (offset, vel) = match step.split_once(':') {
+        Some((offset, vel)) => {
+            let vel: u8 = vel.parse().map_err(|_| invalid())?;
+            if vel == 0 || vel > 127 {
+                return Err(invalid());
+            }
+            (offset, Some(vel as f32 / 127.0))
+        }
+        None => (step, None),
+    };
+    let offset = offset.parse().map_err(|_| invalid())?;
+    Ok(Some(SequenceStep { offset, vel }))
+}
+
+/// Plays a sequence transposed by the last held key.
+pub struct Sequencer {
+    sequence: Sequence,
+    stepper: Stepper,
+}
+
+impl Sequencer {
+    pub fn new(sequence: Sequence, timing: StepTiming, sample_rate: f32) -> Self {
+        Self {
+            sequence,
+            stepper: Stepper::new(timing, sample_rate),
+        }
+    }
+}
+
+impl MidiEffect for Sequencer {
+    fn handle_event(&mut self, event: SynthEvent, emit: &mut dyn FnMut(SynthEvent)) {
+        if let Some(event) = self.stepper.handle_event(event) {
+            emit(event);
+        }
+    }
+
+    fn step_frame(&mut self, mut emit: &mut dyn FnMut(SynthEvent)) {
+        let steps = &self.sequence.0;
+        let step = match self.stepper.step_frame(&mut emit) {
+            Some(step) if !steps.is_empty() => step,
+            _ => return,
+        };
+        let (key, held_vel) = *self.stepper.held().last().unwrap();
+        if let Some(seq_step) = steps[step.index.rem_euclid(steps.len() as i64) as usize] {
+            let key = key as i32 + seq_step.offset as i32;
+            if (0..128).contains(&key) {
+                let vel = seq_step.vel.unwrap_or(held_vel);
+                self.stepper.play(key as u8, vel, &mut emit);
+            }
+        }
+    }
+}
+
+/// Retriggers the held notes on every step.
+pub struct NoteRepeat {
+    stepper: Stepper,
+}
+
+impl NoteRepeat {
+    pub fn new(timing: StepTiming, sample_rate: f32) -> Self {
+        Self {
+            stepper: Stepper::new(timing, sample_rate),
+        }
+    }
+}
+
+impl MidiEffect for NoteRepeat {
+    fn handle_event(&mut self, event: SynthEvent, emit: &mut dyn FnMut(SynthEvent)) {
+        if let Some(event) = self.stepper.handle_event(event) {
+            emit(event);
+        }
+    }
+
+    fn step_frame(&mut self, mut emit: &mut dyn FnMut(SynthEvent)) {
+        if self.stepper.step_frame(&mut emit).is_some() {
+            for i in 0..self.stepper.held().len() {
+                let (key, vel) = self.stepper.held()[i];
+                self.stepper.play(key, vel, &mut emit);
+            }
+        }
+    }
+}
+
+/// Runs a chain of MIDI effects in front of a part, sending it the notes they
+/// play as they happen rather than once per buffer.
+pub struct MidiEffectsPart {
+    effects: Vec<Box<dyn MidiEffect>>,
+    input: mpsc::Receiver<SynthEvent>,
+    output: mpsc::SyncSender<SynthEvent>,
+    inner: Box<dyn Part>,
+}
+
+impl MidiEffectsPart {
+    /// Events go through `effects` in order. `output` is the event queue of
+    /// `inner`.
+    pub fn new(
+        effects: Vec<Box<dyn MidiEffect>>,
+        input: mpsc::Receiver<SynthEvent>,
+        output: mpsc::SyncSender<SynthEvent>,
+        inner: Box<dyn Part>,
+    ) -> Self {
+        Self {
+            effects,
+            input,
+            output,
+            inner,
+        }
+    }
+}
+
+/// Passes an event through the rest of the chain.
+fn run_chain(
+    effects: &mut [Box<dyn MidiEffect>],
+    event: SynthEvent,
+    output: &mpsc::SyncSender<SynthEvent>,
+) {
+    match effects.split_first_mut() {
+        Some((effect, rest)) => {
+            effect.handle_event(event, &mut |event| run_chain(rest, event, output));
+        }
+        None => {
+            log_if_error("MIDI effects send failed", output.try_send(event));
+        }
+    }
+}
+
+impl Part for MidiEffectsPart {
+    fn begin_buffer(&mut self) {
+        while let Ok(event) = self.input.try_recv() {
+            run_chain(&mut self.effects, event, &self.output);
+        }
+        self.inner.begin_buffer();
+    }
+
+    fn pump_events(&mut self) {
+        self.inner.pump_events();
+    }
+
+    fn step_frame(&mut self) -> (f32, f32) {
+        let mut emitted = false;
+        for i in 0..self.effects.len() {
+            let (head, rest) = self.effects.split_at_mut(i + 1);
+            let output = &self.output;
+            head[i].step_frame(&mut |event| {
+                run_chain(rest, event, output);
+                emitted = true;
+            });
+        }
+        if emitted {
+            self.inner.pump_events();
+        }
+        self.inner.step_frame()
+    }
+
+    fn gain_reduction_meter(&self) -> Option<Arc<AtomicU32>> {
+        self.inner.gain_reduction_meter()
+    }
+
+    fn missing_program(&self) -> Option<Arc<AtomicU32>> {
+        self.inner.missing_program()
+    }
+}
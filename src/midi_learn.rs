@@ -0,0 +1,80 @@
+use std::io::BufRead;
+use std::sync::mpsc;
+
+use crate::midi;
+
+/// Assigns controllers by example: after `learn <param>` is typed on stdin,
+/// the next controller moved on the controller channel is bound to it.
+pub struct MidiLearn {
+    commands: mpsc::Receiver<String>,
+    controller_channel: u8,
+    names: Vec<&'static str>,
+    learning: Option<&'static str>,
+}
+
+impl MidiLearn {
+    /// Starts reading commands from stdin. `names` are the parameters that can
+    /// be learned.
+    pub fn new(controller_channel: u8, names: Vec<&'static str>) -> Self {
+        let (sender, commands) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        println!("MIDI learn: type `learn <param>` and move a control, `cancel` to stop.");
+        Self {
+            commands,
+            controller_channel,
+            names,
+            learning: None,
+        }
+    }
+
+    /// Handles commands typed since the last call.
+    pub fn poll_commands(&mut self) {
+        while let Ok(line) = self.commands.try_recv() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("learn"), Some(name)) => match self.names.iter().find(|n| **n == name) {
+                    Some(name) => {
+                        println!(
+                            "move a control on channel {} to assign {}",
+                            self.controller_channel, name
+                        );
+                        self.learning = Some(name);
+                    }
+                    None => eprintln!(
+                        "unknown parameter {}, known: {}",
+                        name,
+                        self.names.join(", ")
+                    ),
+                },
+                (Some("cancel"), None) => self.learning = None,
+                (None, _) => {}
+                _ => eprintln!("unknown command {:?}", line),
+            }
+        }
+    }
+
+    /// Returns the parameter and controller to bind when the event completes a
+    /// learn.
+    pub fn handle_midi_event(&mut self, event: &midi::Event) -> Option<(&'static str, u8)> {
+        match event.content {
+            midi::EventContent::Controller { controller, .. }
+                if event.channel == self.controller_channel =>
+            {
+                self.learning.take().map(|name| (name, controller))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::util::*;
+
+/// Identifies a parameter of an engine, its index in the engine's parameter
+/// table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ParamId(pub usize);
+
+/// How the normalized [0, 1] range of a control maps onto a parameter.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    /// Equal ratios per step, for times, frequencies and the like.
+    Exponential,
+    /// The range is split evenly into integer steps.
+    Stepped,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Unit {
+    None,
+    /// Values in [0, 1], displayed as a percentage.
+    Percent,
+    Seconds,
+    Milliseconds,
+    Hz,
+    Db,
+    Semitones,
+    Ratio,
+    /// Off or on.
+    Toggle,
+    /// An index into these labels.
+    Choice(&'static [&'static str]),
+}
+
+#[derive(Debug, Clone)]
+pub struct ParamInfo {
+    pub name: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub unit: Unit,
+    pub curve: Curve,
+    /// Time constant of the smoothing applied to changes, in milliseconds.
+    pub smoothing_ms: f32,
+}
+
+/// Smoothing used unless a parameter asks for something else. Long enough to
+/// avoid zipper noise on most parameters without feeling sluggish.
+pub const DEFAULT_SMOOTHING_MS: f32 = 5.0;
+
+impl ParamInfo {
+    pub const fn linear(name: &'static str, min: f32, max: f32, default: f32, unit: Unit) -> Self {
+        Self {
+            name,
+            min,
+            max,
+            default,
+            unit,
+            curve: Curve::Linear,
+            smoothing_ms: DEFAULT_SMOOTHING_MS,
+        }
+    }
+
+    /// The range must not contain zero.
+    pub const fn exponential(
+        name: &'static str,
+        min: f32,
+        max: f32,
+        default: f32,
+        unit: Unit,
+    ) -> Self {
+        Self {
+            name,
+            min,
+            max,
+            default,
+            unit,
+            curve: Curve::Exponential,
+            smoothing_ms: DEFAULT_SMOOTHING_MS,
+        }
+    }
+
+    pub const fn toggle(name: &'static str, default: bool) -> Self {
+        Self {
+            name,
+            min: 0.0,
+            max: 1.0,
+            default: if default { 1.0 } else { 0.0 },
+            unit: Unit::Toggle,
+            curve: Curve::Stepped,
+            smoothing_ms: 0.0,
+        }
+    }
+
+    pub const fn choice(
+        name: &'static str,
+        labels: &'static [&'static str],
+        default: usize,
+    ) -> Self {
+        Self {
+            name,
+            min: 0.0,
+            max: (labels.len() - 1) as f32,
+            default: default as f32,
+            unit: Unit::Choice(labels),
+            curve: Curve::Stepped,
+            smoothing_ms: 0.0,
+        }
+    }
+
+    pub const fn with_smoothing(self, smoothing_ms: f32) -> Self {
+        Self {
+            smoothing_ms,
+            ..self
+        }
+    }
+
+    /// A smoother for this parameter, starting at its default.
+    pub fn smoothed(&self, sample_rate: f32) -> Smoothed {
+        Smoothed::new(self.default, self.smoothing_ms, sample_rate)
+    }
+
+    /// Maps a control position in [0, 1] to the parameter's value.
+    pub fn denormalize(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self.curve {
+            Curve::Linear => x.mix(self.min, self.max),
+            Curve::Exponential => x.mixexp(self.min, self.max),
+            Curve::Stepped => {
+                let steps = self.max - self.min + 1.0;
+                self.min + (x * steps).floor().min(steps - 1.0)
+            }
+        }
+    }
+
+    /// Maps a value back to the control position in [0, 1].
+    pub fn normalize(&self, value: f32) -> f32 {
+        let value = value.clamp(self.min.min(self.max), self.max.max(self.min));
+        match self.curve {
+            Curve::Linear => (value - self.min) / (self.max - self.min),
+            Curve::Exponential => (value / self.min).ln() / (self.max / self.min).ln(),
+            Curve::Stepped => (value - self.min + 0.5) / (self.max - self.min + 1.0),
+        }
+    }
+
+    pub fn format(&self, value: f32) -> String {
+        match self.unit {
+            Unit::None => format!("{:.2}", value),
+            Unit::Percent => format!("{:.0}%", value * 100.0),
+            Unit::Seconds if value < 1.0 => format!("{:.0} ms", value * 1000.0),
+            Unit::Seconds => format!("{:.2} s", value),
+            Unit::Milliseconds => format!("{:.1} ms", value),
+            Unit::Hz if value >= 1000.0 => format!("{:.2} kHz", value / 1000.0),
+            Unit::Hz => format!("{:.2} Hz", value),
+            Unit::Db => format!("{:+.1} dB", value),
+            Unit::Semitones => format!("{:+.2} st", value),
+            Unit::Ratio => format!("{:.1}:1", value),
+            Unit::Toggle => (if value > 0.5 { "on" } else { "off" }).to_owned(),
+            Unit::Choice(labels) => {
+                let idx = (value.round().max(0.0) as usize).min(labels.len() - 1);
+                labels[idx].to_owned()
+            }
+        }
+    }
+}
+
+/// A value gliding towards its target with a one-pole filter. The time is
+/// given in milliseconds, so the glide sounds the same at every sample rate.
+#[derive(Debug, Clone)]
+pub struct Smoothed {
+    value: f32,
+    target: f32,
+    coef: f32,
+}
+
+impl Smoothed {
+    pub fn new(value: f32, time_ms: f32, sample_rate: f32) -> Self {
+        let mut s = Self {
+            value,
+            target: value,
+            coef: 0.0,
+        };
+        s.set_time(time_ms, sample_rate);
+        s
+    }
+
+    pub fn set_time(&mut self, time_ms: f32, sample_rate: f32) {
+        let samples = time_ms / 1000.0 * sample_rate;
+        self.coef = if samples > 1.0 {
+            (-1.0 / samples).exp()
+        } else {
+            0.0
+        };
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> f32 {
+        self.value
+    }
+
+    #[inline(always)]
+    pub fn is_settled(&self) -> bool {
+        self.value == self.target
+    }
+
+    /// Advances one sample. Once within a tiny distance of the target the
+    /// value snaps to it and further steps cost only a comparison.
+    #[inline(always)]
+    pub fn step(&mut self) -> f32 {
+        if self.value != self.target {
+            let next = self.target + self.coef * (self.value - self.target);
+            // Rounding can stall the glide just short of the target.
+            let close = (next - self.target).abs() <= 1e-5 * self.target.abs().max(1e-3);
+            self.value = if close || next == self.value {
+                self.target
+            } else {
+                next
+            };
+        }
+        self.value
+    }
+}
+
+/// Declares the parameters of an engine: an enum naming them and the table
+/// with their info, in the same order so the enum doubles as the `ParamId`.
+macro_rules! declare_params {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident, $table:ident {
+        $($variant:ident => $info:expr,)*
+    }) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        $vis enum $name {
+            $($variant,)*
+        }
+
+        impl $name {
+            const ALL: &'static [$name] = &[$($name::$variant,)*];
+
+            pub fn from_id(id: $crate::params::ParamId) -> Option<Self> {
+                Self::ALL.get(id.0).copied()
+            }
+
+            pub fn id(self) -> $crate::params::ParamId {
+                $crate::params::ParamId(self as usize)
+            }
+
+            pub fn info(self) -> &'static $crate::params::ParamInfo {
+                &$table[self as usize]
+            }
+        }
+
+        $vis const $table: &[$crate::params::ParamInfo] = &[$($info,)*];
+    };
+}
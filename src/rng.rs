@@ -15,7 +15,7 @@ impl Xoroshiro {
     pub fn next(&mut self) -> u64 {
         let s0 = self.s0;
         let mut s1 = self.s1;
-        let r = s0 + s1;
+        let r = s0.wrapping_add(s1);
 
         s1 ^= s0;
         self.s0 = s0.rotate_left(24) ^ s1 ^ (s1 << 16);
@@ -1,9 +1,34 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+
+use crate::clock::Transport;
+use crate::params::ParamId;
+
 pub trait Synth: Send + Sync {
     type Voice: Voice<Self>;
 
-    fn param_change(&mut self, param: u8, value: f32);
+    /// Sets a parameter from the engine's parameter table, in its own units.
+    fn set_param(&mut self, param: ParamId, value: f32);
+    fn program_change(&mut self, _program: u8) {}
+    /// Bends all voices, in semitones.
+    fn pitch_bend(&mut self, _semitones: f32) {}
+    /// Follows the tempo and position of an external sequencer, sent on every
+    /// clock tick and transport change.
+    fn transport(&mut self, _transport: Transport) {}
     fn notify_buffer(&mut self);
+    /// A meter of the gain reduction of a master bus compressor, see
+    /// `Compressor::gain_reduction_meter`.
+    fn gain_reduction_meter(&self) -> Option<Arc<AtomicU32>> {
+        None
+    }
+    /// Raised to one more than the program of a program change that selected
+    /// nothing, so the main loop can report it. Zero when there's nothing to
+    /// report.
+    fn missing_program(&self) -> Option<Arc<AtomicU32>> {
+        None
+    }
     fn step_frame(&mut self);
+    fn process_master(&mut self, l: f32, r: f32) -> (f32, f32);
 }
 
 pub trait Voice<S: Synth + ?Sized>: Send + Sync {
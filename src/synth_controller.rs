@@ -1,6 +1,9 @@
 use slotmap::{DefaultKey, DenseSlotMap, Key};
-use std::sync::mpsc;
+use std::sync::atomic::AtomicU32;
+use std::sync::{mpsc, Arc};
 
+use crate::clock::Transport;
+use crate::params::ParamId;
 use crate::synth::{Synth, Voice};
 
 const MAX_CHANNELS: usize = 64;
@@ -9,7 +12,10 @@ const MAX_CHANNELS: usize = 64;
 pub enum SynthEvent {
     NoteOn { key: u8, vel: f32 },
     NoteOff { key: u8 },
-    ParamChange { param: u8, value: f32 },
+    ParamChange { param: ParamId, value: f32 },
+    ProgramChange { program: u8 },
+    PitchBend { semitones: f32 },
+    Transport { transport: Transport },
 }
 
 #[derive(Debug)]
@@ -97,7 +103,19 @@ impl<S: Synth> SynthController<S> {
                 }
 
                 SynthEvent::ParamChange { param, value } => {
-                    synth.param_change(param, value);
+                    synth.set_param(param, value);
+                }
+
+                SynthEvent::ProgramChange { program } => {
+                    synth.program_change(program);
+                }
+
+                SynthEvent::PitchBend { semitones } => {
+                    synth.pitch_bend(semitones);
+                }
+
+                SynthEvent::Transport { transport } => {
+                    synth.transport(transport);
                 }
             }
         }
@@ -111,6 +129,56 @@ impl<S: Synth> SynthController<S> {
             left += l;
             right += r;
         }
-        (left, right)
+        synth.process_master(left, right)
+    }
+}
+
+/// A synth together with the controller playing its voices, so several
+/// synths can share one output stream.
+pub trait Part: Send {
+    fn begin_buffer(&mut self);
+    /// Applies queued events now instead of at the next buffer, for events
+    /// sent from the audio thread.
+    fn pump_events(&mut self);
+    fn step_frame(&mut self) -> (f32, f32);
+    fn gain_reduction_meter(&self) -> Option<Arc<AtomicU32>>;
+    fn missing_program(&self) -> Option<Arc<AtomicU32>>;
+}
+
+pub struct SynthPart<S: Synth> {
+    synth: S,
+    controller: SynthController<S>,
+}
+
+impl<S: Synth> SynthPart<S> {
+    pub fn new(synth: S, event_queue: mpsc::Receiver<SynthEvent>) -> Self {
+        Self {
+            synth,
+            controller: SynthController::new(event_queue),
+        }
+    }
+}
+
+impl<S: Synth> Part for SynthPart<S> {
+    fn begin_buffer(&mut self) {
+        self.controller.pump_events(&mut self.synth);
+        self.synth.notify_buffer();
+    }
+
+    fn pump_events(&mut self) {
+        self.controller.pump_events(&mut self.synth);
+    }
+
+    fn step_frame(&mut self) -> (f32, f32) {
+        self.synth.step_frame();
+        self.controller.step_all_voices(&mut self.synth)
+    }
+
+    fn gain_reduction_meter(&self) -> Option<Arc<AtomicU32>> {
+        self.synth.gain_reduction_meter()
+    }
+
+    fn missing_program(&self) -> Option<Arc<AtomicU32>> {
+        self.synth.missing_program()
     }
 }
@@ -1,93 +1,180 @@
-use crate::util::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 
-const MAX_WINDOW_SIZE: usize = 5000;
+use crate::util::*;
 
+/// A feed-forward stereo bus compressor with soft knee and lookahead.
+///
+/// The detector sees the undelayed input while the audio path is delayed by
+/// the lookahead time, so the gain can already be reduced when a transient
+/// arrives at the output.
 pub struct Compressor {
     sample_rate: f32,
-    ringbuffer: [f32; MAX_WINDOW_SIZE],
-    ringbuffer_idx: usize,
-    window_size: usize,
-
-    threshold: f32,
-    att_rate: f32,
-    rel_rate: f32,
-    max_gain: f32,
-    clean_mix: f32,
-
-    rms2total: f32,
-    gain: f32,
+
+    // Allocated for the longest lookahead, only the first delay_len frames
+    // are used.
+    delay_line: Vec<(f32, f32)>,
+    delay_len: usize,
+    delay_idx: usize,
+
+    threshold_db: f32,
+    ratio: f32,
+    knee_db: f32,
+    att_coef: f32,
+    rel_coef: f32,
+    makeup_gain: f32,
+    stereo_link: f32,
+
+    // Current smoothed gain reduction in dB (always <= 0) per channel.
+    reduction_db: (f32, f32),
+    // Largest gain reduction seen since the meter was last published.
+    meter_db: f32,
+    meter: Arc<AtomicU32>,
 }
 
 impl Compressor {
-    pub fn new(sample_rate: f32, window_time_ms: f32) -> Self {
-        let window_size = (sample_rate * window_time_ms / 1000.0) as usize;
-        if window_size >= MAX_WINDOW_SIZE {
-            panic!("compressor buffer size insufficient for sample rate and window size")
-        }
+    pub fn new(sample_rate: f32, max_lookahead_ms: f32) -> Self {
+        let max_delay_len = ((sample_rate * max_lookahead_ms / 1000.0) as usize).max(1);
 
         let mut c = Self {
             sample_rate,
-            ringbuffer: [0.0; MAX_WINDOW_SIZE],
-            ringbuffer_idx: 0,
-            window_size,
-
-            threshold: 0.7,
-            att_rate: 0.0, // Initialized later.
-            rel_rate: 0.0, // Initialized later.
-            max_gain: 0.0, // Initialized later.
-            clean_mix: 0.0,
-
-            rms2total: 0.0,
-            gain: 2.0,
-        };
 
-        c.set_attack_time(20.0);
-        c.set_release_time(20.0);
-        c.set_max_gain(-6.0);
+            delay_line: vec![(0.0, 0.0); max_delay_len],
+            delay_len: max_delay_len,
+            delay_idx: 0,
+
+            threshold_db: -12.0,
+            ratio: 4.0,
+            knee_db: 6.0,
+            att_coef: 0.0, // Initialized later.
+            rel_coef: 0.0, // Initialized later.
+            makeup_gain: 1.0,
+            stereo_link: 1.0,
+
+            reduction_db: (0.0, 0.0),
+            meter_db: 0.0,
+            meter: Arc::new(AtomicU32::new(0)),
+        };
 
+        c.set_lookahead(5.0);
+        c.set_attack_time(10.0);
+        c.set_release_time(100.0);
         c
     }
 
-    pub fn set_threshold(&mut self, threshold: f32) {
-        self.threshold = threshold;
+    /// The latency introduced by the lookahead, in frames.
+    pub fn latency(&self) -> usize {
+        self.delay_len
+    }
+
+    /// Set the lookahead in ms, up to the maximum given at construction.
+    pub fn set_lookahead(&mut self, lookahead_ms: f32) {
+        let len = (self.sample_rate * lookahead_ms / 1000.0) as usize;
+        self.delay_len = len.clamp(1, self.delay_line.len());
+        if self.delay_idx >= self.delay_len {
+            self.delay_idx = 0;
+        }
+    }
+
+    /// Set the threshold in db.
+    pub fn set_threshold(&mut self, threshold_db: f32) {
+        self.threshold_db = threshold_db;
+    }
+
+    /// Set the compression ratio, e.g. 4.0 for 4:1. Values below 1 are clamped.
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.ratio = ratio.max(1.0);
+    }
+
+    /// Set the width of the soft knee in db, centered around the threshold.
+    pub fn set_knee(&mut self, knee_db: f32) {
+        self.knee_db = knee_db.max(0.0);
     }
 
-    /// Set the attack time in ms/6db.
+    /// Set the attack time in ms.
     pub fn set_attack_time(&mut self, att_time_ms: f32) {
-        self.att_rate = 2.0f32.powf(-1.0 / (att_time_ms / 1000.0 * self.sample_rate));
+        self.att_coef = time_constant_coef(att_time_ms, self.sample_rate);
     }
 
-    /// Set the release time in ms/6db.
+    /// Set the release time in ms.
     pub fn set_release_time(&mut self, rel_time_ms: f32) {
-        self.rel_rate = 2.0f32.powf(1.0 / (rel_time_ms / 1000.0 * self.sample_rate));
+        self.rel_coef = time_constant_coef(rel_time_ms, self.sample_rate);
     }
 
-    /// Set the maximum gain in db.
-    pub fn set_max_gain(&mut self, max_gain: f32) {
-        self.max_gain = 2.0f32.powf(-max_gain / 6.0);
+    /// Set the make-up gain in db.
+    pub fn set_makeup_gain(&mut self, makeup_db: f32) {
+        self.makeup_gain = makeup_db.db_to_gain();
     }
 
-    pub fn set_clean_mix(&mut self, clean_mix: f32) {
-        self.clean_mix = clean_mix;
+    /// Set how strongly the channels share their detector, 0.0 being fully
+    /// independent and 1.0 being fully linked (same gain on both channels).
+    pub fn set_stereo_link(&mut self, link: f32) {
+        self.stereo_link = link.clamp(0.0, 1.0);
     }
 
-    pub fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
-        let sqvol = l * l + r * r;
-        self.rms2total -= self.ringbuffer[self.ringbuffer_idx];
-        self.rms2total += sqvol;
-        self.ringbuffer[self.ringbuffer_idx] = sqvol;
-        self.ringbuffer_idx = (self.ringbuffer_idx + 1) % self.window_size;
-
-        let rms2 = self.rms2total / self.window_size as f32;
-        self.gain *= if rms2 > self.threshold {
-            self.att_rate
+    /// The largest gain reduction in db (as a positive number) as f32 bits,
+    /// raised by `publish_meter`. The reader is expected to reset it after
+    /// reporting.
+    pub fn gain_reduction_meter(&self) -> Arc<AtomicU32> {
+        self.meter.clone()
+    }
+
+    /// Hands the gain reduction seen since the last call to the meter. Once
+    /// per buffer is plenty.
+    pub fn publish_meter(&mut self) {
+        // Positive floats order the same as their bits.
+        let reduction = std::mem::replace(&mut self.meter_db, 0.0);
+        self.meter.fetch_max(reduction.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Static gain curve, returns the gain change in db for an input level in db.
+    fn gain_computer(&self, level_db: f32) -> f32 {
+        let over = level_db - self.threshold_db;
+        let slope = 1.0 / self.ratio - 1.0;
+        if 2.0 * over <= -self.knee_db {
+            0.0
+        } else if 2.0 * over < self.knee_db {
+            let x = over + self.knee_db / 2.0;
+            slope * x * x / (2.0 * self.knee_db)
+        } else {
+            slope * over
+        }
+    }
+
+    fn smooth(&self, current: f32, target: f32) -> f32 {
+        // Reduction is negative, so a lower target means we're attacking.
+        let coef = if target < current {
+            self.att_coef
         } else {
-            self.rel_rate
+            self.rel_coef
         };
-        self.gain = self.gain.clamp(0.0, self.max_gain);
+        coef.mix(target, current).flush_denormal()
+    }
+
+    pub fn process(&mut self, l: f32, r: f32) -> (f32, f32) {
+        let (al, ar) = (l.abs(), r.abs());
+        let linked = al.max(ar);
+        let det_l = self.stereo_link.mix(al, linked);
+        let det_r = self.stereo_link.mix(ar, linked);
+
+        let target_l = self.gain_computer(det_l.max(1e-9).gain_to_db());
+        let target_r = self.gain_computer(det_r.max(1e-9).gain_to_db());
+        self.reduction_db.0 = self.smooth(self.reduction_db.0, target_l);
+        self.reduction_db.1 = self.smooth(self.reduction_db.1, target_r);
 
-        let cl = self.clean_mix.mix(l * self.gain, l);
-        let cr = self.clean_mix.mix(r * self.gain, r);
-        (cl, cr)
+        let reduction = -self.reduction_db.0.min(self.reduction_db.1);
+        self.meter_db = self.meter_db.max(reduction);
+
+        let (dl, dr) = std::mem::replace(&mut self.delay_line[self.delay_idx], (l, r));
+        self.delay_idx = (self.delay_idx + 1) % self.delay_len;
+
+        let gl = self.reduction_db.0.db_to_gain() * self.makeup_gain;
+        let gr = self.reduction_db.1.db_to_gain() * self.makeup_gain;
+        (dl * gl, dr * gr)
     }
 }
+
+/// One-pole coefficient reaching 1 - 1/e of a step in the given time.
+fn time_constant_coef(time_ms: f32, sample_rate: f32) -> f32 {
+    (-1.0 / (time_ms.max(0.01) / 1000.0 * sample_rate)).exp()
+}
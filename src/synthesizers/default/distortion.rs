@@ -0,0 +1,200 @@
+use crate::util::*;
+
+// Non-zero side taps of a 31-tap Kaiser windowed half-band lowpass, the
+// centre tap is 0.5 and every other tap is zero.
+const HB_HALF_LEN: usize = 8;
+const HB_TAPS: [f32; HB_HALF_LEN] = [
+    0.31433344,
+    -0.09460322,
+    0.04605905,
+    -0.02374255,
+    0.01162484,
+    -0.00503746,
+    0.00176030,
+    -0.00039440,
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Shape {
+    HardClip,
+    Tanh,
+    Tube,
+    Foldback,
+    Bitcrush,
+    Downsample,
+}
+
+impl Shape {
+    pub fn from_index(idx: usize) -> Self {
+        const SHAPES: [Shape; 6] = [
+            Shape::HardClip,
+            Shape::Tanh,
+            Shape::Tube,
+            Shape::Foldback,
+            Shape::Bitcrush,
+            Shape::Downsample,
+        ];
+        SHAPES[idx.min(SHAPES.len() - 1)]
+    }
+}
+
+/// Delay line for one polyphase branch of a half-band filter.
+#[derive(Clone)]
+struct HalfBandBranch {
+    history: [f32; 2 * HB_HALF_LEN],
+}
+
+impl HalfBandBranch {
+    fn new() -> Self {
+        Self {
+            history: [0.0; 2 * HB_HALF_LEN],
+        }
+    }
+
+    fn push(&mut self, x: f32) {
+        self.history.copy_within(1.., 0);
+        self.history[2 * HB_HALF_LEN - 1] = x;
+    }
+
+    fn center(&self) -> f32 {
+        self.history[HB_HALF_LEN]
+    }
+
+    fn side_taps(&self) -> f32 {
+        let mut acc = 0.0;
+        for (m, c) in HB_TAPS.iter().enumerate() {
+            acc += c * (self.history[HB_HALF_LEN - 1 - m] + self.history[HB_HALF_LEN + m]);
+        }
+        acc
+    }
+}
+
+/// Half-band 2x upsampler.
+#[derive(Clone)]
+struct Upsampler(HalfBandBranch);
+
+impl Upsampler {
+    fn process(&mut self, x: f32) -> (f32, f32) {
+        self.0.push(x);
+        (2.0 * self.0.side_taps(), self.0.center())
+    }
+}
+
+/// Half-band 2x decimator.
+#[derive(Clone)]
+struct Downsampler {
+    even: HalfBandBranch,
+    odd: HalfBandBranch,
+}
+
+impl Downsampler {
+    fn process(&mut self, x0: f32, x1: f32) -> f32 {
+        self.even.push(x0);
+        self.odd.push(x1);
+        0.5 * self.even.center() + self.odd.side_taps()
+    }
+}
+
+/// Runs a function at 1x, 2x or 4x the sample rate using cascaded half-band
+/// filters.
+#[derive(Clone)]
+pub struct Oversampler {
+    up: [Upsampler; 2],
+    down: [Downsampler; 2],
+}
+
+impl Oversampler {
+    pub fn new() -> Self {
+        let up = Upsampler(HalfBandBranch::new());
+        let down = Downsampler {
+            even: HalfBandBranch::new(),
+            odd: HalfBandBranch::new(),
+        };
+        Self {
+            up: [up.clone(), up],
+            down: [down.clone(), down],
+        }
+    }
+
+    pub fn process(&mut self, x: f32, factor: usize, mut f: impl FnMut(f32) -> f32) -> f32 {
+        match factor {
+            1 => f(x),
+            2 => {
+                let (a, b) = self.up[0].process(x);
+                self.down[0].process(f(a), f(b))
+            }
+            _ => {
+                let (a, b) = self.up[0].process(x);
+                let (a0, a1) = self.up[1].process(a);
+                let (b0, b1) = self.up[1].process(b);
+                let a = self.down[1].process(f(a0), f(a1));
+                let b = self.down[1].process(f(b0), f(b1));
+                self.down[0].process(a, b)
+            }
+        }
+    }
+}
+
+/// Per-voice waveshaping distortion.
+pub struct Distortion {
+    oversampler: Oversampler,
+    hold_value: f32,
+    hold_phase: f32,
+}
+
+impl Distortion {
+    pub fn new() -> Self {
+        Self {
+            oversampler: Oversampler::new(),
+            hold_value: 0.0,
+            hold_phase: 1.0,
+        }
+    }
+
+    /// Distorts a sample. Pregain, level and mix are the raw [0, 1] parameters.
+    pub fn process(
+        &mut self,
+        x: f32,
+        shape: Shape,
+        oversampling: usize,
+        pregain: f32,
+        level: f32,
+        mix: f32,
+    ) -> f32 {
+        let pregain = pregain.mix(-8.0, 8.0).db_to_gain();
+        let max_ampl = (-10.0 * (1.0 - level)).db_to_gain();
+
+        if shape == Shape::Downsample {
+            // Sample rate reduction aliases by design, no point oversampling.
+            let hold = (1.0 - level).mixexp(1.0, 64.0);
+            self.hold_phase += 1.0;
+            if self.hold_phase >= hold {
+                self.hold_phase -= hold;
+                self.hold_value = x * pregain;
+            }
+            return mix.mix(x, self.hold_value);
+        }
+
+        let bits = level.mix(2.0, 12.0);
+        let steps = 2.0f32.powf(bits - 1.0);
+        self.oversampler.process(x, oversampling, |x| {
+            let u = x * pregain;
+            let shaped = match shape {
+                Shape::HardClip => u.clamp(-max_ampl, max_ampl),
+                Shape::Tanh => max_ampl * (u / max_ampl).tanh(),
+                Shape::Tube => {
+                    // Biased tanh, asymmetric so it adds even harmonics.
+                    const BIAS: f32 = 0.3;
+                    max_ampl * ((u / max_ampl + BIAS).tanh() - BIAS.tanh())
+                }
+                Shape::Foldback => {
+                    let v = u / max_ampl;
+                    max_ampl * (1.0 - ((v + 1.0).rem_euclid(4.0) - 2.0).abs())
+                }
+                Shape::Bitcrush => (u.clamp(-1.0, 1.0) * steps).round() / steps,
+                Shape::Downsample => unreachable!(),
+            };
+            mix.mix(x, shaped)
+        })
+    }
+}
@@ -1,3 +1,5 @@
+use crate::util::*;
+
 fn tanhxdx(x: f64) -> f64 {
     // x.tanh() / x
     let a = x * x;
@@ -87,6 +89,9 @@ impl MystramFilter {
         self.s[1] += 2.0 * self.f * (y0 - y1);
         self.s[2] += 2.0 * self.f * (y1 - y2);
         self.s[3] += 2.0 * self.f * (y2 - t4 * y3);
+        for s in self.s.iter_mut() {
+            *s = s.flush_denormal();
+        }
 
         y3
     }
@@ -1,101 +1,197 @@
-mod button_map;
 mod compressor;
+mod distortion;
 mod low_pass;
-mod rng;
+mod noise;
+mod params;
+mod wavetable;
 
+use crate::rng::Xoroshiro;
 use crate::util::*;
 use anyhow::Result;
-use button_map::ButtonMap;
-use rng::Xoroshiro;
+use params::{Param, PARAMS};
 use std::error::Error;
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+use wavetable::{pulse_warp, pulse_warp_speedup, Wavetable};
 
+use super::{Engine, EngineOption};
+use crate::clock::Transport;
+use crate::params::{ParamId, Smoothed};
 use crate::synth::{Synth, Voice};
+use crate::synth_controller::{Part, SynthPart};
 
 const HEADROOM: f32 = 0.25;
+const MAX_FM_INDEX: f32 = 8.0;
+const MIN_PULSE_WIDTH: f32 = 0.02;
+// Bend messages arrive in coarse steps, often only every few milliseconds.
+const PITCH_BEND_SMOOTHING_MS: f32 = 10.0;
+const MAX_COMPRESSOR_LOOKAHEAD_MS: f32 = 20.0;
+// Beats per PWM LFO cycle for each pwm_sync setting, 0 when free running.
+const PWM_SYNC_BEATS: [f64; 6] = [0.0, 4.0, 2.0, 1.0, 0.5, 0.25];
+
+/// How oscillator 2 modulates oscillator 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OscModMode {
+    None,
+    /// Linear through-zero FM, osc2 modulates the frequency of osc1.
+    Fm,
+    /// Osc1 is multiplied by osc2.
+    Ring,
+    /// Osc1 restarts its cycle whenever osc2 does.
+    Sync,
+}
+
+impl OscModMode {
+    fn from_index(idx: usize) -> Self {
+        match idx {
+            0 => OscModMode::None,
+            1 => OscModMode::Fm,
+            2 => OscModMode::Ring,
+            _ => OscModMode::Sync,
+        }
+    }
+}
+
+pub const ENGINE: Engine = Engine {
+    name: "default",
+    description: "Two oscillator subtractive synthesizer.",
+    options: &[
+        EngineOption {
+            name: "osc1-wavetable",
+            help: "WAV file with single-cycle frames of 2048 samples for oscillator 1.",
+        },
+        EngineOption {
+            name: "osc2-wavetable",
+            help: "WAV file with single-cycle frames of 2048 samples for oscillator 2.",
+        },
+    ],
+    params: PARAMS,
+    build: |config, event_queue| -> Result<Box<dyn Part>> {
+        let synth = DefaultSynth::new(
+            config.sample_rate,
+            config.get("osc1-wavetable"),
+            config.get("osc2-wavetable"),
+        )?;
+        Ok(Box::new(SynthPart::new(synth, event_queue)))
+    },
+};
 
 pub struct DefaultSynth {
-    button_map: ButtonMap,
     sample_rate: f32,
-    rng_state: rng::Xoroshiro,
+    rng_state: Xoroshiro,
 
     key_velocity: bool,
 
-    target_master_volume: f32,
-    master_volume: f32,
-
-    target_attack_time: f32,
-    target_decay_time: f32,
-    target_sustain: f32,
-    target_release_time: f32,
-    attack_time: f32,
-    decay_time: f32,
-    sustain: f32,
-    release_time: f32,
-
-    osc1_waveform: f32,
-    osc2_waveform: f32,
-    target_osc_balance: f32,
-    osc_balance: f32,
-
-    target_filter_cutoff: f32,
-    target_filter_resonance: f32,
-    filter_cutoff: f32,
-    filter_resonance: f32,
-
-    target_distortion_pregain: f32,
-    target_distortion_level: f32,
-    target_distortion_mix: f32,
-    distortion_pregain: f32,
-    distortion_level: f32,
-    distortion_mix: f32,
+    master_volume: Smoothed,
+
+    attack_time: Smoothed,
+    decay_time: Smoothed,
+    sustain: Smoothed,
+    release_time: Smoothed,
+
+    osc1_table: Wavetable,
+    osc2_table: Wavetable,
+    osc1_waveform: Smoothed,
+    osc2_waveform: Smoothed,
+    osc_balance: Smoothed,
+    osc1_pulse_width: Smoothed,
+    osc2_pulse_width: Smoothed,
+    pwm_rate: Smoothed,
+    pwm_depth: Smoothed,
+    pwm_lfo_t: f32,
+    pwm_sync_beats: f64,
+    // Pulse widths after modulation, what the voices use.
+    osc1_width: f32,
+    osc2_width: f32,
+    osc1_tune: Smoothed,
+    // In semitones, and the frequency ratio it works out to.
+    pitch_bend: Smoothed,
+    pitch_bend_ratio: f32,
+    osc_mod_mode: OscModMode,
+    osc_mod_amount: Smoothed,
+
+    noise_color: noise::NoiseColor,
+    noise_level: Smoothed,
+
+    // Position of the external sequencer in beats, advanced between clock
+    // ticks at its tempo.
+    tempo: Option<f32>,
+    beat: f64,
+
+    filter_cutoff: Smoothed,
+    filter_resonance: Smoothed,
+
+    distortion_pregain: Smoothed,
+    distortion_level: Smoothed,
+    distortion_mix: Smoothed,
+    distortion_shape: distortion::Shape,
+    distortion_oversampling: usize,
 
     filter_relative: bool,
     enable_compressor: bool,
+    compressor: compressor::Compressor,
 }
 
 impl DefaultSynth {
-    pub fn new(config_file: &str, sample_rate: f32) -> Result<Self> {
+    pub fn new(
+        sample_rate: f32,
+        osc1_wavetable: Option<&str>,
+        osc2_wavetable: Option<&str>,
+    ) -> Result<Self> {
+        let load_table = |path: Option<&str>| match path {
+            Some(path) => Wavetable::from_wav(path),
+            None => Ok(Wavetable::basic()),
+        };
+
         Ok(Self {
-            button_map: ButtonMap::from_toml(config_file)?,
             sample_rate,
             rng_state: Xoroshiro::new(42),
 
             key_velocity: true,
 
-            target_master_volume: 1.0,
-            master_volume: 1.0,
-
-            target_attack_time: 0.01,
-            attack_time: 0.01,
-
-            target_decay_time: 0.01,
-            decay_time: 0.01,
-
-            target_sustain: 1.0,
-            sustain: 1.0,
-
-            target_release_time: 0.01,
-            release_time: 0.01,
-
-            osc1_waveform: 0.0,
-            osc2_waveform: 0.0,
-            target_osc_balance: 0.5,
-            osc_balance: 0.5,
-
-            target_filter_cutoff: 1.0,
-            filter_cutoff: 1.0,
-
-            target_filter_resonance: 0.5,
-            filter_resonance: 0.5,
-
-            target_distortion_pregain: 0.0,
-            target_distortion_level: 0.0,
-            target_distortion_mix: 0.0,
-            distortion_pregain: 0.0,
-            distortion_level: 0.0,
-            distortion_mix: 0.0,
+            master_volume: Param::MasterVolume.info().smoothed(sample_rate),
+
+            attack_time: Param::VolumeAttack.info().smoothed(sample_rate),
+            decay_time: Param::VolumeDecay.info().smoothed(sample_rate),
+            sustain: Param::VolumeSustain.info().smoothed(sample_rate),
+            release_time: Param::VolumeRelease.info().smoothed(sample_rate),
+
+            osc1_table: load_table(osc1_wavetable)?,
+            osc2_table: load_table(osc2_wavetable)?,
+            osc1_waveform: Param::Osc1Waveform.info().smoothed(sample_rate),
+            osc2_waveform: Param::Osc2Waveform.info().smoothed(sample_rate),
+            osc_balance: Param::OscBalance.info().smoothed(sample_rate),
+            osc1_pulse_width: Param::Osc1PulseWidth.info().smoothed(sample_rate),
+            osc2_pulse_width: Param::Osc2PulseWidth.info().smoothed(sample_rate),
+            pwm_rate: Param::PwmRate.info().smoothed(sample_rate),
+            pwm_depth: Param::PwmDepth.info().smoothed(sample_rate),
+            pwm_lfo_t: 0.0,
+            pwm_sync_beats: 0.0,
+            osc1_width: 0.5,
+            osc2_width: 0.5,
+            osc1_tune: Param::Osc1Tune.info().smoothed(sample_rate),
+            pitch_bend: Smoothed::new(0.0, PITCH_BEND_SMOOTHING_MS, sample_rate),
+            pitch_bend_ratio: 1.0,
+            osc_mod_mode: OscModMode::None,
+            osc_mod_amount: Param::OscModAmount.info().smoothed(sample_rate),
+
+            noise_color: noise::NoiseColor::White,
+            noise_level: Param::NoiseLevel.info().smoothed(sample_rate),
+
+            tempo: None,
+            beat: 0.0,
+
+            filter_cutoff: Param::FilterCutoff.info().smoothed(sample_rate),
+            filter_resonance: Param::FilterResonance.info().smoothed(sample_rate),
+
+            distortion_pregain: Param::DistortionPregain.info().smoothed(sample_rate),
+            distortion_level: Param::DistortionLevel.info().smoothed(sample_rate),
+            distortion_mix: Param::DistortionMix.info().smoothed(sample_rate),
+            distortion_shape: distortion::Shape::HardClip,
+            distortion_oversampling: 2,
 
             enable_compressor: false,
+            compressor: compressor::Compressor::new(sample_rate, MAX_COMPRESSOR_LOOKAHEAD_MS),
             filter_relative: false,
         })
     }
@@ -104,57 +200,128 @@ impl DefaultSynth {
 impl Synth for DefaultSynth {
     type Voice = DefaultVoice;
 
-    fn param_change(&mut self, param: u8, value: f32) {
-        if param == self.button_map.master_volume {
-            self.target_master_volume = value;
-        } else if param == self.button_map.key_velocity {
-            self.key_velocity = value > 0.5;
-        } else if param == self.button_map.volume_attack {
-            self.target_attack_time = value.mixexp(0.01, 5.0);
-        } else if param == self.button_map.volume_decay {
-            self.target_decay_time = value.mixexp(0.01, 5.0);
-        } else if param == self.button_map.volume_sustain {
-            self.target_sustain = value;
-        } else if param == self.button_map.volume_release {
-            self.target_release_time = value.mixexp(0.01, 5.0);
-        } else if param == self.button_map.osc1_waveform {
-            self.osc1_waveform = value;
-        } else if param == self.button_map.osc2_waveform {
-            self.osc2_waveform = value;
-        } else if param == self.button_map.osc_balance {
-            self.target_osc_balance = value;
-        } else if param == self.button_map.filter_cutoff {
-            self.target_filter_cutoff = value;
-        } else if param == self.button_map.filter_resonance {
-            self.target_filter_resonance = value;
-        } else if param == self.button_map.enable_compressor {
-            self.enable_compressor = value > 0.5;
-        } else if param == self.button_map.filter_relative {
-            self.filter_relative = value > 0.5;
-        } else if param == self.button_map.distortion_pregain {
-            self.target_distortion_pregain = value;
-        } else if param == self.button_map.distortion_level {
-            self.target_distortion_level = value;
-        } else if param == self.button_map.distortion_mix {
-            self.target_distortion_mix = value;
+    fn set_param(&mut self, param: ParamId, value: f32) {
+        let param = match Param::from_id(param) {
+            Some(p) => p,
+            None => return,
+        };
+
+        match param {
+            Param::MasterVolume => self.master_volume.set_target(value),
+            Param::KeyVelocity => self.key_velocity = value > 0.5,
+            Param::VolumeAttack => self.attack_time.set_target(value),
+            Param::VolumeDecay => self.decay_time.set_target(value),
+            Param::VolumeSustain => self.sustain.set_target(value),
+            Param::VolumeRelease => self.release_time.set_target(value),
+            Param::Osc1Waveform => self.osc1_waveform.set_target(value),
+            Param::Osc2Waveform => self.osc2_waveform.set_target(value),
+            Param::OscBalance => self.osc_balance.set_target(value),
+            Param::Osc1PulseWidth => self.osc1_pulse_width.set_target(value),
+            Param::Osc2PulseWidth => self.osc2_pulse_width.set_target(value),
+            Param::PwmRate => self.pwm_rate.set_target(value),
+            Param::PwmDepth => self.pwm_depth.set_target(value),
+            Param::PwmSync => {
+                let idx = (value as usize).min(PWM_SYNC_BEATS.len() - 1);
+                self.pwm_sync_beats = PWM_SYNC_BEATS[idx];
+            }
+            Param::Osc1Tune => self.osc1_tune.set_target(value),
+            Param::OscModMode => self.osc_mod_mode = OscModMode::from_index(value as usize),
+            Param::OscModAmount => self.osc_mod_amount.set_target(value),
+            Param::NoiseColor => self.noise_color = noise::NoiseColor::from_index(value as usize),
+            Param::NoiseLevel => self.noise_level.set_target(value),
+            Param::FilterCutoff => self.filter_cutoff.set_target(value),
+            Param::FilterResonance => self.filter_resonance.set_target(value),
+            Param::FilterRelative => self.filter_relative = value > 0.5,
+            Param::EnableCompressor => self.enable_compressor = value > 0.5,
+            Param::CompressorThreshold => self.compressor.set_threshold(value),
+            Param::CompressorRatio => self.compressor.set_ratio(value),
+            Param::CompressorKnee => self.compressor.set_knee(value),
+            Param::CompressorAttack => self.compressor.set_attack_time(value),
+            Param::CompressorRelease => self.compressor.set_release_time(value),
+            Param::CompressorMakeup => self.compressor.set_makeup_gain(value),
+            Param::CompressorStereoLink => self.compressor.set_stereo_link(value),
+            Param::CompressorLookahead => self.compressor.set_lookahead(value),
+            Param::DistortionPregain => self.distortion_pregain.set_target(value),
+            Param::DistortionLevel => self.distortion_level.set_target(value),
+            Param::DistortionMix => self.distortion_mix.set_target(value),
+            Param::DistortionShape => {
+                self.distortion_shape = distortion::Shape::from_index(value as usize)
+            }
+            Param::DistortionOversampling => {
+                self.distortion_oversampling = 1 << (value as usize).min(2)
+            }
+        }
+    }
+
+    fn pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend.set_target(semitones);
+    }
+
+    fn transport(&mut self, transport: Transport) {
+        self.tempo = transport.tempo;
+        // While stopped the position stays put, keep the LFO moving.
+        if transport.playing {
+            self.beat = transport.beat;
         }
     }
 
-    fn notify_buffer(&mut self) {}
+    fn notify_buffer(&mut self) {
+        self.compressor.publish_meter();
+    }
+
+    fn gain_reduction_meter(&self) -> Option<Arc<AtomicU32>> {
+        Some(self.compressor.gain_reduction_meter())
+    }
 
     fn step_frame(&mut self) {
-        self.attack_time = 0.95 * self.attack_time + 0.05 * self.target_attack_time;
-        self.decay_time = 0.95 * self.decay_time + 0.05 * self.target_decay_time;
-        self.sustain = 0.95 * self.sustain + 0.05 * self.target_sustain;
-        self.release_time = 0.95 * self.release_time + 0.05 * self.target_release_time;
-        self.master_volume = 0.95 * self.master_volume + 0.05 * self.target_master_volume;
-        self.osc_balance = 0.95 * self.osc_balance + 0.05 * self.target_osc_balance;
-        self.filter_cutoff = 0.95 * self.filter_cutoff + 0.05 * self.target_filter_cutoff;
-        self.filter_resonance = 0.95 * self.filter_resonance + 0.05 * self.target_filter_resonance;
-        self.distortion_pregain =
-            0.95 * self.distortion_pregain + 0.05 * self.target_distortion_pregain;
-        self.distortion_level = 0.95 * self.distortion_level + 0.05 * self.target_distortion_level;
-        self.distortion_mix = 0.95 * self.distortion_mix + 0.05 * self.target_distortion_mix;
+        self.attack_time.step();
+        self.decay_time.step();
+        self.sustain.step();
+        self.release_time.step();
+        self.master_volume.step();
+        self.osc1_waveform.step();
+        self.osc2_waveform.step();
+        self.osc_balance.step();
+        self.osc1_tune.step();
+        if !self.pitch_bend.is_settled() {
+            self.pitch_bend_ratio = (self.pitch_bend.step() / 12.0).exp2();
+        }
+        self.osc1_pulse_width.step();
+        self.osc2_pulse_width.step();
+        self.pwm_rate.step();
+        self.pwm_depth.step();
+
+        // Free-running LFO shared by all voices, like on the classic string
+        // machines.
+        if let Some(tempo) = self.tempo {
+            self.beat += tempo as f64 / 60.0 / self.sample_rate as f64;
+        }
+        if self.pwm_sync_beats > 0.0 && self.tempo.is_some() {
+            self.pwm_lfo_t = (self.beat / self.pwm_sync_beats).rem_euclid(1.0) as f32;
+        } else {
+            self.pwm_lfo_t = (self.pwm_lfo_t + self.pwm_rate.get() / self.sample_rate) % 1.0;
+        }
+        let lfo = self.pwm_depth.get() * (self.pwm_lfo_t * 2.0 * std::f32::consts::PI).sin();
+        let max_width = 1.0 - MIN_PULSE_WIDTH;
+        self.osc1_width =
+            (self.osc1_pulse_width.get() + lfo * 0.5).clamp(MIN_PULSE_WIDTH, max_width);
+        self.osc2_width =
+            (self.osc2_pulse_width.get() + lfo * 0.5).clamp(MIN_PULSE_WIDTH, max_width);
+        self.osc_mod_amount.step();
+        self.noise_level.step();
+        self.filter_cutoff.step();
+        self.filter_resonance.step();
+        self.distortion_pregain.step();
+        self.distortion_level.step();
+        self.distortion_mix.step();
+    }
+
+    fn process_master(&mut self, l: f32, r: f32) -> (f32, f32) {
+        if self.enable_compressor {
+            self.compressor.process(l, r)
+        } else {
+            (l, r)
+        }
     }
 }
 
@@ -164,33 +331,31 @@ pub struct DefaultVoice {
     released: bool,
 
     t: f32,
-    wave_t: f32,
+    osc1_t: f32,
+    osc2_t: f32,
     pre_release_volume: f32,
     release_time: f32,
 
     low_pass: low_pass::MystramFilter,
-    compressor: compressor::Compressor,
-
-    rng_state: Xoroshiro,
+    distortion: distortion::Distortion,
+    noise: noise::Noise,
 }
 
 impl Voice<DefaultSynth> for DefaultVoice {
     fn new(pitch: f32, vel: f32, synth: &mut DefaultSynth) -> Self {
-        let compressor = compressor::Compressor::new(synth.sample_rate, 50.0);
-
         Self {
             pitch,
             vel: if synth.key_velocity { vel } else { 1.0 },
             released: false,
             t: 0.0,
-            wave_t: 0.0,
+            osc1_t: 0.0,
+            osc2_t: 0.0,
             release_time: 0.0,
             pre_release_volume: 0.0,
 
             low_pass: low_pass::MystramFilter::new(synth.sample_rate as f64),
-            compressor,
-
-            rng_state: Xoroshiro::new(synth.rng_state.next()),
+            distortion: distortion::Distortion::new(),
+            noise: noise::Noise::new(synth.rng_state.next()),
         }
     }
 
@@ -199,94 +364,101 @@ impl Voice<DefaultSynth> for DefaultVoice {
 
         if self.released {
             let dt = self.t - self.release_time;
-            let release_perc = (dt / synth.release_time).clamp(0.0, 1.0);
-            adsr = (1.0 - release_perc).powi(2) * self.pre_release_volume;
+            let release_perc = (dt / synth.release_time.get()).clamp(0.0, 1.0);
+            adsr = ((1.0 - release_perc).powi(2) * self.pre_release_volume).flush_denormal();
         } else {
-            let attack_perc = (self.t / synth.attack_time).clamp(0.0, 1.0);
+            let attack_perc = (self.t / synth.attack_time.get()).clamp(0.0, 1.0);
             adsr = 1.0 - (1.0 - attack_perc).powi(2);
             self.pre_release_volume = adsr;
         }
 
-        let volume = self.vel * synth.master_volume * adsr * HEADROOM;
-
-        let osc1 = if synth.osc1_waveform < 0.25 {
-            // Sine wave.
-            (self.wave_t * 2.0 * std::f32::consts::PI).sin()
-        } else if synth.osc1_waveform < 0.5 {
-            // Sawtooth.
-            if self.wave_t < 0.5 {
-                2.0 * self.wave_t
-            } else {
-                2.0 * (self.wave_t - 0.5) - 1.0
-            }
-        } else if synth.osc1_waveform < 0.75 {
-            // Square.
-            if self.wave_t < 0.5 {
-                1.0
-            } else {
-                -1.0
-            }
+        let volume = self.vel * synth.master_volume.get() * adsr * HEADROOM;
+
+        let pitch = self.pitch * synth.pitch_bend_ratio;
+        let osc1_pitch = pitch * (synth.osc1_tune.get() / 12.0).exp2();
+        let osc1_max_pitch = if synth.osc_mod_mode == OscModMode::Fm {
+            // The sidebands of FM extend past the carrier, be conservative.
+            osc1_pitch + synth.osc_mod_amount.get() * MAX_FM_INDEX * pitch
         } else {
-            // Noise.
-            2.0 * self.rng_state.next_float() - 1.0
+            osc1_pitch
         };
-
-        let osc2 = if synth.osc2_waveform < 0.25 {
-            // Sine wave.
-            (self.wave_t * 2.0 * std::f32::consts::PI).sin()
-        } else if synth.osc2_waveform < 0.5 {
-            // Sawtooth.
-            if self.wave_t < 0.5 {
-                2.0 * self.wave_t
-            } else {
-                2.0 * (self.wave_t - 0.5) - 1.0
-            }
-        } else if synth.osc2_waveform < 0.75 {
-            // Square.
-            if self.wave_t < 0.5 {
-                1.0
-            } else {
-                -1.0
-            }
+        let osc1_mip_level = Wavetable::mip_level(
+            osc1_max_pitch * pulse_warp_speedup(synth.osc1_width),
+            synth.sample_rate,
+        );
+        let osc2_mip_level = Wavetable::mip_level(
+            pitch * pulse_warp_speedup(synth.osc2_width),
+            synth.sample_rate,
+        );
+
+        let osc2 = synth.osc2_table.sample(
+            osc2_mip_level,
+            synth.osc2_waveform.get(),
+            pulse_warp(self.osc2_t, synth.osc2_width),
+        );
+        let osc1 = synth.osc1_table.sample(
+            osc1_mip_level,
+            synth.osc1_waveform.get(),
+            pulse_warp(self.osc1_t, synth.osc1_width),
+        );
+        let osc1 = if synth.osc_mod_mode == OscModMode::Ring {
+            synth.osc_mod_amount.get().mix(osc1, osc1 * osc2)
         } else {
-            // Noise.
-            2.0 * self.rng_state.next_float() - 1.0
+            osc1
         };
 
-        let val = (1.0 - synth.osc_balance) * osc1 + synth.osc_balance * osc2;
+        let val = (1.0 - synth.osc_balance.get()) * osc1 + synth.osc_balance.get() * osc2;
+        let val = if synth.noise_level.get() > 0.0 {
+            val + synth.noise_level.get() * self.noise.next(synth.noise_color)
+        } else {
+            val
+        };
 
         // Distort.
-        let max_ampl = (-10.0 * (1.0 - synth.distortion_level)).db_to_gain();
-        let distorted = (val * synth.distortion_pregain.mix(-8.0, 8.0).db_to_gain())
-            .clamp(-max_ampl, max_ampl);
-        let val = synth.distortion_mix.mix(val, distorted);
+        let val = self.distortion.process(
+            val,
+            synth.distortion_shape,
+            synth.distortion_oversampling,
+            synth.distortion_pregain.get(),
+            synth.distortion_level.get(),
+            synth.distortion_mix.get(),
+        );
 
         if synth.filter_relative {
             self.low_pass
-                .set_cutoff((self.pitch * synth.filter_cutoff.mixexp(1.0, 4.0)) as f64);
+                .set_cutoff((pitch * synth.filter_cutoff.get().mixexp(1.0, 4.0)) as f64);
         } else {
             self.low_pass
-                .set_cutoff(synth.filter_cutoff.mixexp(20.0, 25000.0) as f64);
+                .set_cutoff(synth.filter_cutoff.get().mixexp(20.0, 25000.0) as f64);
         }
-        self.low_pass.set_resonance(synth.filter_resonance as f64);
+        self.low_pass
+            .set_resonance(synth.filter_resonance.get() as f64);
         let val = self.low_pass.process(val as f64) as f32;
 
-        // Compress.
-        let (val, _) = if synth.enable_compressor {
-            self.compressor.process(val, val)
+        // Increment time.
+        let osc1_freq = if synth.osc_mod_mode == OscModMode::Fm {
+            // Through-zero: the instantaneous frequency may go negative, in
+            // which case the phase simply runs backwards.
+            osc1_pitch + synth.osc_mod_amount.get() * MAX_FM_INDEX * pitch * osc2
         } else {
-            (val, val)
+            osc1_pitch
         };
-
-        // Increment time.
-        self.wave_t += self.pitch / synth.sample_rate;
-        self.wave_t %= 1.0;
+        self.osc1_t = (self.osc1_t + osc1_freq / synth.sample_rate).rem_euclid(1.0);
+        self.osc2_t += pitch / synth.sample_rate;
+        if self.osc2_t >= 1.0 {
+            self.osc2_t -= 1.0;
+            if synth.osc_mod_mode == OscModMode::Sync {
+                // Place osc1 where it would be had it restarted exactly at the
+                // sub-sample moment osc2 wrapped.
+                self.osc1_t = (self.osc2_t * osc1_pitch / pitch).rem_euclid(1.0);
+            }
+        }
         self.t += 1.0 / synth.sample_rate;
 
         let val = val * 5.0;
 
-        const EAR_SAFETY: f32 = 0.80;
-        let wave = (val * volume).clamp(-EAR_SAFETY, EAR_SAFETY);
+        // Peaks are handled by the limiter on the summed output.
+        let wave = val * volume;
         (wave, wave)
     }
 
@@ -298,7 +470,7 @@ impl Voice<DefaultSynth> for DefaultVoice {
     fn is_done(&self, synth: &DefaultSynth) -> bool {
         if self.released {
             let dt = self.t - self.release_time;
-            dt >= synth.release_time
+            dt >= synth.release_time.get()
         } else {
             false
         }
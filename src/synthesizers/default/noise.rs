@@ -0,0 +1,65 @@
+use crate::rng::Xoroshiro;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NoiseColor {
+    White,
+    Pink,
+    Brown,
+}
+
+impl NoiseColor {
+    pub fn from_index(idx: usize) -> Self {
+        match idx {
+            0 => NoiseColor::White,
+            1 => NoiseColor::Pink,
+            _ => NoiseColor::Brown,
+        }
+    }
+}
+
+/// Noise source producing roughly unit-peak white, pink or brown noise.
+pub struct Noise {
+    rng: Xoroshiro,
+    pink: [f32; 7],
+    brown: f32,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Xoroshiro::new(seed),
+            pink: [0.0; 7],
+            brown: 0.0,
+        }
+    }
+
+    pub fn next(&mut self, color: NoiseColor) -> f32 {
+        let white = 2.0 * self.rng.next_float() - 1.0;
+        match color {
+            NoiseColor::White => white,
+
+            NoiseColor::Pink => {
+                // Paul Kellet's refined -3dB/octave filter, accurate to within
+                // 0.05dB above 9.2Hz at 44.1kHz.
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.153852;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                pink * 0.11
+            }
+
+            NoiseColor::Brown => {
+                // Leaky integrator, the leak keeps it from drifting off. The
+                // input gain puts the peaks near 1, the clamp only catches the
+                // rare longer excursion.
+                self.brown = (0.98 * self.brown + 0.07 * white).clamp(-1.0, 1.0);
+                self.brown
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::params::{ParamInfo, Unit};
+
+declare_params!(pub enum Param, PARAMS {
+    MasterVolume => ParamInfo::linear("master_volume", 0.0, 1.0, 1.0, Unit::Percent)
+        .with_smoothing(10.0),
+    KeyVelocity => ParamInfo::toggle("key_velocity", true),
+    VolumeAttack => ParamInfo::exponential("volume_attack", 0.01, 5.0, 0.01, Unit::Seconds),
+    VolumeDecay => ParamInfo::exponential("volume_decay", 0.01, 5.0, 0.01, Unit::Seconds),
+    VolumeSustain => ParamInfo::linear("volume_sustain", 0.0, 1.0, 1.0, Unit::Percent),
+    VolumeRelease => ParamInfo::exponential("volume_release", 0.01, 5.0, 0.01, Unit::Seconds),
+
+    // Position within the oscillator's wavetable.
+    Osc1Waveform => ParamInfo::linear("osc1_waveform", 0.0, 1.0, 0.0, Unit::Percent)
+        .with_smoothing(20.0),
+    Osc2Waveform => ParamInfo::linear("osc2_waveform", 0.0, 1.0, 0.0, Unit::Percent)
+        .with_smoothing(20.0),
+    OscBalance => ParamInfo::linear("osc_balance", 0.0, 1.0, 0.5, Unit::Percent),
+    Osc1PulseWidth => ParamInfo::linear("osc1_pulse_width", 0.5, 0.98, 0.5, Unit::Percent),
+    Osc2PulseWidth => ParamInfo::linear("osc2_pulse_width", 0.5, 0.98, 0.5, Unit::Percent),
+    PwmRate => ParamInfo::exponential("pwm_rate", 0.05, 20.0, 1.0, Unit::Hz)
+        .with_smoothing(50.0),
+    PwmDepth => ParamInfo::linear("pwm_depth", 0.0, 1.0, 0.0, Unit::Percent),
+    // Locks the PWM LFO to the MIDI clock, one cycle per this many beats.
+    PwmSync => ParamInfo::choice(
+        "pwm_sync",
+        &["off", "4 beats", "2 beats", "1 beat", "1/2 beat", "1/4 beat"],
+        0,
+    ),
+    Osc1Tune => ParamInfo::linear("osc1_tune", 0.0, 24.0, 0.0, Unit::Semitones)
+        .with_smoothing(20.0),
+    OscModMode => ParamInfo::choice("osc_mod_mode", &["none", "fm", "ring", "sync"], 0),
+    OscModAmount => ParamInfo::linear("osc_mod_amount", 0.0, 1.0, 0.0, Unit::Percent),
+
+    NoiseColor => ParamInfo::choice("noise_color", &["white", "pink", "brown"], 0),
+    NoiseLevel => ParamInfo::linear("noise_level", 0.0, 1.0, 0.0, Unit::Percent),
+
+    DistortionPregain => ParamInfo::linear("distortion_pregain", 0.0, 1.0, 0.0, Unit::Percent),
+    DistortionLevel => ParamInfo::linear("distortion_level", 0.0, 1.0, 0.0, Unit::Percent),
+    DistortionMix => ParamInfo::linear("distortion_mix", 0.0, 1.0, 0.0, Unit::Percent),
+    DistortionShape => ParamInfo::choice(
+        "distortion_shape",
+        &["hard clip", "tanh", "tube", "foldback", "bitcrush", "downsample"],
+        0,
+    ),
+    DistortionOversampling => ParamInfo::choice("distortion_oversampling", &["1x", "2x", "4x"], 1),
+
+    FilterCutoff => ParamInfo::linear("filter_cutoff", 0.0, 1.0, 1.0, Unit::Percent)
+        .with_smoothing(20.0),
+    FilterResonance => ParamInfo::linear("filter_resonance", 0.0, 1.0, 0.5, Unit::Percent)
+        .with_smoothing(20.0),
+    FilterRelative => ParamInfo::toggle("filter_relative", false),
+
+    EnableCompressor => ParamInfo::toggle("enable_compressor", false),
+    CompressorThreshold => ParamInfo::linear("compressor_threshold", -40.0, 0.0, -12.0, Unit::Db),
+    CompressorRatio => ParamInfo::exponential("compressor_ratio", 1.0, 20.0, 4.0, Unit::Ratio),
+    CompressorKnee => ParamInfo::linear("compressor_knee", 0.0, 24.0, 6.0, Unit::Db),
+    CompressorAttack => {
+        ParamInfo::exponential("compressor_attack", 0.1, 100.0, 10.0, Unit::Milliseconds)
+    },
+    CompressorRelease => {
+        ParamInfo::exponential("compressor_release", 10.0, 1000.0, 100.0, Unit::Milliseconds)
+    },
+    CompressorMakeup => ParamInfo::linear("compressor_makeup", 0.0, 24.0, 0.0, Unit::Db),
+    // How much the channels share their detector, 0 compresses them independently.
+    CompressorStereoLink => {
+        ParamInfo::linear("compressor_stereo_link", 0.0, 1.0, 1.0, Unit::Percent)
+    },
+    CompressorLookahead => {
+        ParamInfo::linear("compressor_lookahead", 0.0, 20.0, 5.0, Unit::Milliseconds)
+    },
+});
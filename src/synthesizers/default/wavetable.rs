@@ -0,0 +1,212 @@
+use anyhow::{bail, Result};
+use std::f64::consts::PI;
+
+use crate::util::*;
+use crate::wav::read_wav;
+
+/// Samples per single-cycle frame, the same as Serum-style wavetables.
+pub const FRAME_SIZE: usize = 2048;
+/// One mip level per octave, from FRAME_SIZE / 2 harmonics down to one.
+const NUM_LEVELS: usize = 11;
+
+/// A single-cycle frame stored at every octave of band-limiting. Each table
+/// has one extra guard sample so interpolation never has to wrap.
+struct Frame {
+    levels: Vec<Vec<f32>>,
+}
+
+impl Frame {
+    fn new(samples: &[f32]) -> Self {
+        let mut spectrum: Vec<(f64, f64)> = samples.iter().map(|&s| (s as f64, 0.0)).collect();
+        fft(&mut spectrum, false);
+        // Drop DC, an offset is never useful in an oscillator.
+        spectrum[0] = (0.0, 0.0);
+
+        let levels = (0..NUM_LEVELS)
+            .map(|level| {
+                let max_harmonic = (FRAME_SIZE / 2) >> level;
+                let mut bins = spectrum.clone();
+                for h in max_harmonic + 1..=FRAME_SIZE / 2 {
+                    bins[h] = (0.0, 0.0);
+                    bins[FRAME_SIZE - h] = (0.0, 0.0);
+                }
+                fft(&mut bins, true);
+
+                let mut table: Vec<f32> = bins
+                    .iter()
+                    .map(|(re, _)| (re / FRAME_SIZE as f64) as f32)
+                    .collect();
+                table.push(table[0]);
+                table
+            })
+            .collect();
+        Self { levels }
+    }
+
+    #[inline(always)]
+    fn read(&self, level: usize, phase: f32) -> f32 {
+        let table = &self.levels[level];
+        let pos = phase * FRAME_SIZE as f32;
+        let idx = (pos as usize).min(FRAME_SIZE - 1);
+        (pos - idx as f32).mix(table[idx], table[idx + 1])
+    }
+}
+
+/// A set of single-cycle frames that can be morphed between.
+pub struct Wavetable {
+    frames: Vec<Frame>,
+}
+
+impl Wavetable {
+    /// Sine, triangle, saw and square, in that order.
+    pub fn basic() -> Self {
+        let shapes: [fn(f64) -> f64; 4] = [
+            |t| (2.0 * PI * t).sin(),
+            |t| 1.0 - 4.0 * (t - 0.5).abs(),
+            |t| 2.0 * t - 1.0,
+            |t| if t < 0.5 { 1.0 } else { -1.0 },
+        ];
+        let frames: Vec<Vec<f32>> = shapes
+            .iter()
+            .map(|shape| {
+                (0..FRAME_SIZE)
+                    .map(|i| shape(i as f64 / FRAME_SIZE as f64) as f32)
+                    .collect()
+            })
+            .collect();
+        Self::from_frames(&frames)
+    }
+
+    /// Loads a WAV file consisting of one or more concatenated frames of
+    /// FRAME_SIZE samples. A file shorter than that is treated as a single
+    /// cycle and resampled. Only the first channel is used.
+    pub fn from_wav(path: &str) -> Result<Self> {
+        let (spec, samples) = read_wav(path)?;
+        let mono: Vec<f32> = samples
+            .iter()
+            .step_by(spec.channels as usize)
+            .copied()
+            .collect();
+
+        if mono.is_empty() {
+            bail!("wavetable {} is empty", path);
+        }
+
+        let frames: Vec<Vec<f32>> = if mono.len() < FRAME_SIZE {
+            vec![(0..FRAME_SIZE)
+                .map(|i| {
+                    let pos = i as f32 * mono.len() as f32 / FRAME_SIZE as f32;
+                    let idx = pos as usize;
+                    let next = mono[(idx + 1) % mono.len()];
+                    (pos - idx as f32).mix(mono[idx], next)
+                })
+                .collect()]
+        } else {
+            let tail = mono.len() % FRAME_SIZE;
+            if tail > 0 {
+                eprintln!(
+                    "wavetable {} is not a multiple of {} samples, ignoring last {}",
+                    path, FRAME_SIZE, tail
+                );
+            }
+            mono.chunks_exact(FRAME_SIZE).map(|c| c.to_vec()).collect()
+        };
+
+        Ok(Self::from_frames(&frames))
+    }
+
+    fn from_frames(frames: &[Vec<f32>]) -> Self {
+        let mut frames: Vec<Frame> = frames.iter().map(|f| Frame::new(f)).collect();
+
+        // Normalize the table as a whole so morphing doesn't change loudness
+        // more than the source material does.
+        let peak = frames
+            .iter()
+            .flat_map(|f| f.levels[0].iter())
+            .fold(0.0f32, |m, s| m.max(s.abs()));
+        if peak > 0.0 {
+            for level in frames.iter_mut().flat_map(|f| f.levels.iter_mut()) {
+                for s in level.iter_mut() {
+                    *s /= peak;
+                }
+            }
+        }
+
+        Self { frames }
+    }
+
+    /// Picks the mip level with the most harmonics that all stay below Nyquist.
+    pub fn mip_level(pitch: f32, sample_rate: f32) -> usize {
+        let harmonics = (FRAME_SIZE / 2) as f32 * pitch / (sample_rate / 2.0);
+        (harmonics.max(1.0).log2().ceil() as usize).min(NUM_LEVELS - 1)
+    }
+
+    /// Reads the table at the given phase in [0, 1), morphing between frames
+    /// with position in [0, 1].
+    #[inline(always)]
+    pub fn sample(&self, level: usize, position: f32, phase: f32) -> f32 {
+        let pos = position.clamp(0.0, 1.0) * (self.frames.len() - 1) as f32;
+        let idx = (pos as usize).min(self.frames.len() - 1);
+        let a = self.frames[idx].read(level, phase);
+        if idx + 1 < self.frames.len() {
+            let b = self.frames[idx + 1].read(level, phase);
+            (pos - idx as f32).mix(a, b)
+        } else {
+            a
+        }
+    }
+}
+
+/// Warps the phase so the first half of the cycle takes up `width` of the
+/// period. On a square this gives a pulse of that duty cycle, on other shapes
+/// it skews the waveform.
+#[inline(always)]
+pub fn pulse_warp(phase: f32, width: f32) -> f32 {
+    if phase < width {
+        0.5 * phase / width
+    } else {
+        0.5 + 0.5 * (phase - width) / (1.0 - width)
+    }
+}
+
+/// How much faster than the nominal pitch the warped cycle runs at its
+/// quickest, for choosing a mip level that stays band-limited.
+#[inline(always)]
+pub fn pulse_warp_speedup(width: f32) -> f32 {
+    0.5 / width.min(1.0 - width)
+}
+
+/// In-place iterative radix-2 FFT, the length must be a power of two. The
+/// inverse is unscaled.
+fn fft(buf: &mut [(f64, f64)], inverse: bool) {
+    let n = buf.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f64;
+        for k in 0..len / 2 {
+            let (wr, wi) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+            for start in (0..n).step_by(len) {
+                let (ar, ai) = buf[start + k];
+                let (br, bi) = buf[start + k + len / 2];
+                let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+                buf[start + k] = (ar + tr, ai + ti);
+                buf[start + k + len / 2] = (ar - tr, ai - ti);
+            }
+        }
+        len <<= 1;
+    }
+}
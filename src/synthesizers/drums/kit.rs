@@ -0,0 +1,214 @@
+/// Frequencies of the six square waves in the TR-808 cymbal and hi-hat
+/// circuits, which together give their metallic, inharmonic sound.
+const METAL_808: [f32; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+const COWBELL: [f32; 2] = [540.0, 800.0];
+
+/// A sine tone sweeping exponentially from `start_freq` to `end_freq`.
+#[derive(Debug, Copy, Clone)]
+pub struct Tone {
+    pub start_freq: f32,
+    pub end_freq: f32,
+    pub sweep_time: f32,
+    pub decay: f32,
+    pub level: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterMode {
+    HighPass,
+    BandPass,
+}
+
+/// Filtered white noise, optionally started with a few short bursts as in a
+/// hand clap.
+#[derive(Debug, Copy, Clone)]
+pub struct Noise {
+    pub mode: FilterMode,
+    pub cutoff: f32,
+    pub q: f32,
+    pub decay: f32,
+    pub level: f32,
+    pub bursts: u32,
+}
+
+/// A cluster of square waves, high passed and then band passed.
+#[derive(Debug, Copy, Clone)]
+pub struct Metal {
+    pub partials: &'static [f32],
+    pub highpass: f32,
+    pub bandpass: f32,
+    pub decay: f32,
+    pub level: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Choke {
+    None,
+    /// Closed and pedal hi-hats cut off a ringing open hi-hat.
+    ChokesHat,
+    ChokedByHat,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Patch {
+    pub tone: Option<Tone>,
+    pub overtone: Option<Tone>,
+    pub noise: Option<Noise>,
+    pub metal: Option<Metal>,
+    pub choke: Choke,
+}
+
+const EMPTY: Patch = Patch {
+    tone: None,
+    overtone: None,
+    noise: None,
+    metal: None,
+    choke: Choke::None,
+};
+
+fn kick(start_freq: f32, end_freq: f32, decay: f32) -> Patch {
+    Patch {
+        tone: Some(Tone {
+            start_freq,
+            end_freq,
+            sweep_time: 0.03,
+            decay,
+            level: 0.9,
+        }),
+        // Short click for the beater.
+        noise: Some(Noise {
+            mode: FilterMode::HighPass,
+            cutoff: 2000.0,
+            q: 0.7,
+            decay: 0.003,
+            level: 0.3,
+            bursts: 0,
+        }),
+        ..EMPTY
+    }
+}
+
+fn tom(freq: f32) -> Patch {
+    Patch {
+        tone: Some(Tone {
+            start_freq: 1.6 * freq,
+            end_freq: freq,
+            sweep_time: 0.05,
+            decay: 0.3,
+            level: 0.7,
+        }),
+        noise: Some(Noise {
+            mode: FilterMode::BandPass,
+            cutoff: 4.0 * freq,
+            q: 1.0,
+            decay: 0.02,
+            level: 0.1,
+            bursts: 0,
+        }),
+        ..EMPTY
+    }
+}
+
+fn snare(noise_level: f32) -> Patch {
+    Patch {
+        tone: Some(Tone {
+            start_freq: 240.0,
+            end_freq: 180.0,
+            sweep_time: 0.02,
+            decay: 0.1,
+            level: 0.3,
+        }),
+        overtone: Some(Tone {
+            start_freq: 400.0,
+            end_freq: 330.0,
+            sweep_time: 0.02,
+            decay: 0.07,
+            level: 0.25,
+        }),
+        noise: Some(Noise {
+            mode: FilterMode::HighPass,
+            cutoff: 1500.0,
+            q: 0.7,
+            decay: 0.18,
+            level: noise_level,
+            bursts: 0,
+        }),
+        ..EMPTY
+    }
+}
+
+fn metal(highpass: f32, bandpass: f32, decay: f32, level: f32, choke: Choke) -> Patch {
+    Patch {
+        metal: Some(Metal {
+            partials: &METAL_808,
+            highpass,
+            bandpass,
+            decay,
+            level,
+        }),
+        choke,
+        ..EMPTY
+    }
+}
+
+/// The patch for a General MIDI percussion key, if we have one.
+pub fn gm_patch(key: u8) -> Option<Patch> {
+    let patch = match key {
+        35 => kick(120.0, 45.0, 0.5),
+        36 => kick(150.0, 50.0, 0.4),
+        37 => Patch {
+            tone: Some(Tone {
+                start_freq: 1700.0,
+                end_freq: 1700.0,
+                sweep_time: 0.01,
+                decay: 0.015,
+                level: 0.4,
+            }),
+            noise: Some(Noise {
+                mode: FilterMode::BandPass,
+                cutoff: 3000.0,
+                q: 2.0,
+                decay: 0.01,
+                level: 0.4,
+                bursts: 0,
+            }),
+            ..EMPTY
+        },
+        38 => snare(0.3),
+        40 => snare(0.45),
+        39 => Patch {
+            noise: Some(Noise {
+                mode: FilterMode::BandPass,
+                cutoff: 1100.0,
+                q: 1.5,
+                decay: 0.15,
+                level: 1.5,
+                bursts: 3,
+            }),
+            ..EMPTY
+        },
+        41 => tom(70.0),
+        43 => tom(85.0),
+        45 => tom(100.0),
+        47 => tom(120.0),
+        48 => tom(145.0),
+        50 => tom(175.0),
+        42 => metal(7000.0, 10000.0, 0.05, 1.6, Choke::ChokesHat),
+        44 => metal(7000.0, 10000.0, 0.08, 1.2, Choke::ChokesHat),
+        46 => metal(7000.0, 10000.0, 0.45, 1.6, Choke::ChokedByHat),
+        49 | 57 => metal(5000.0, 8000.0, 1.4, 1.4, Choke::None),
+        51 | 59 => metal(3000.0, 6000.0, 0.9, 1.0, Choke::None),
+        56 => Patch {
+            metal: Some(Metal {
+                partials: &COWBELL,
+                highpass: 400.0,
+                bandpass: 2600.0,
+                decay: 0.3,
+                level: 0.6,
+            }),
+            ..EMPTY
+        },
+        _ => return None,
+    };
+    Some(patch)
+}
@@ -0,0 +1,290 @@
+mod kit;
+
+use anyhow::Result;
+use std::f32::consts::PI;
+
+use super::Engine;
+use crate::params::{ParamId, ParamInfo, Smoothed, Unit};
+use crate::rng::Xoroshiro;
+use crate::synth::{Synth, Voice};
+use crate::synth_controller::{Part, SynthPart};
+use crate::util::*;
+use kit::{Choke, FilterMode, Patch};
+
+/// Envelopes are considered finished at -80 db.
+const SILENCE: f32 = 1e-4;
+
+/// Decay time of a choked open hi-hat.
+const CHOKE_TIME: f32 = 0.005;
+
+declare_params!(enum Param, PARAMS {
+    MasterVolume => ParamInfo::linear("master_volume", 0.0, 1.0, 1.0, Unit::Percent)
+        .with_smoothing(10.0),
+});
+
+pub const ENGINE: Engine = Engine {
+    name: "drums",
+    description: "Synthesized drum kit mapped to the General MIDI percussion keys.",
+    options: &[],
+    params: PARAMS,
+    build: |config, event_queue| -> Result<Box<dyn Part>> {
+        let synth = DrumSynth::new(config.sample_rate);
+        Ok(Box::new(SynthPart::new(synth, event_queue)))
+    },
+};
+
+/// Synthesized drum kit mapped to the General MIDI percussion keys.
+pub struct DrumSynth {
+    sample_rate: f32,
+    voice_ctr: u64,
+    hat_choke_ctr: u64,
+
+    master_volume: Smoothed,
+}
+
+impl DrumSynth {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            sample_rate,
+            voice_ctr: 0,
+            hat_choke_ctr: 0,
+            master_volume: Param::MasterVolume.info().smoothed(sample_rate),
+        }
+    }
+}
+
+impl Synth for DrumSynth {
+    type Voice = DrumVoice;
+
+    fn set_param(&mut self, param: ParamId, value: f32) {
+        if Param::from_id(param) == Some(Param::MasterVolume) {
+            self.master_volume.set_target(value);
+        }
+    }
+
+    fn notify_buffer(&mut self) {}
+
+    fn step_frame(&mut self) {
+        self.master_volume.step();
+    }
+
+    fn process_master(&mut self, l: f32, r: f32) -> (f32, f32) {
+        (l, r)
+    }
+}
+
+/// Topology-preserving transform state variable filter.
+#[derive(Debug, Clone)]
+struct Svf {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1: f32,
+    ic2: f32,
+}
+
+impl Svf {
+    fn new(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let g = (PI * cutoff.min(0.45 * sample_rate) / sample_rate).tan();
+        let k = 1.0 / q;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        Self {
+            k,
+            a1,
+            a2,
+            a3: g * a2,
+            ic1: 0.0,
+            ic2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32, mode: FilterMode) -> f32 {
+        let v3 = x - self.ic2;
+        let v1 = self.a1 * self.ic1 + self.a2 * v3;
+        let v2 = self.ic2 + self.a2 * self.ic1 + self.a3 * v3;
+        self.ic1 = (2.0 * v1 - self.ic1).flush_denormal();
+        self.ic2 = (2.0 * v2 - self.ic2).flush_denormal();
+        match mode {
+            FilterMode::HighPass => x - self.k * v1 - v2,
+            FilterMode::BandPass => v1,
+        }
+    }
+}
+
+fn decay_coef(time: f32, sample_rate: f32) -> f32 {
+    SILENCE.powf(1.0 / (time.max(0.001) * sample_rate))
+}
+
+struct ToneState {
+    phase: f32,
+    sweep: f32,
+    sweep_coef: f32,
+    amp: f32,
+    amp_coef: f32,
+}
+
+impl ToneState {
+    fn new(tone: &kit::Tone, sample_rate: f32) -> Self {
+        Self {
+            phase: 0.0,
+            sweep: 1.0,
+            sweep_coef: decay_coef(tone.sweep_time, sample_rate),
+            amp: tone.level,
+            amp_coef: decay_coef(tone.decay, sample_rate),
+        }
+    }
+
+    fn step(&mut self, tone: &kit::Tone, sample_rate: f32) -> f32 {
+        let freq = self.sweep.mix(tone.end_freq, tone.start_freq);
+        let out = (2.0 * PI * self.phase).sin() * self.amp;
+        self.phase = (self.phase + freq / sample_rate).fract();
+        self.sweep *= self.sweep_coef;
+        self.amp = (self.amp * self.amp_coef).flush_denormal();
+        out
+    }
+}
+
+pub struct DrumVoice {
+    patch: Option<Patch>,
+    gain: f32,
+
+    tone: Option<ToneState>,
+    overtone: Option<ToneState>,
+
+    rng: Xoroshiro,
+    noise_filter: Option<Svf>,
+    noise_amp: f32,
+    noise_coef: f32,
+    bursts_left: u32,
+    burst_timer: u32,
+
+    metal_phases: [f32; 6],
+    metal_highpass: Option<Svf>,
+    metal_bandpass: Option<Svf>,
+    metal_amp: f32,
+    metal_coef: f32,
+    hat_choke_ctr: u64,
+}
+
+/// Samples between the bursts of a clap.
+fn burst_interval(sample_rate: f32) -> u32 {
+    (0.011 * sample_rate) as u32
+}
+
+impl Voice<DrumSynth> for DrumVoice {
+    fn new(pitch: f32, vel: f32, synth: &mut DrumSynth) -> Self {
+        let key = (69.0 + 12.0 * (pitch / 440.0).log2())
+            .round()
+            .clamp(0.0, 127.0) as u8;
+        let patch = kit::gm_patch(key);
+        let sr = synth.sample_rate;
+
+        synth.voice_ctr = synth.voice_ctr.wrapping_add(1);
+        if patch.map(|p| p.choke) == Some(Choke::ChokesHat) {
+            synth.hat_choke_ctr = synth.hat_choke_ctr.wrapping_add(1);
+        }
+
+        let noise = patch.and_then(|p| p.noise);
+        let metal = patch.and_then(|p| p.metal);
+        Self {
+            patch,
+            gain: vel,
+
+            tone: patch.and_then(|p| p.tone).map(|t| ToneState::new(&t, sr)),
+            overtone: patch
+                .and_then(|p| p.overtone)
+                .map(|t| ToneState::new(&t, sr)),
+
+            rng: Xoroshiro::new(synth.voice_ctr),
+            noise_filter: noise.map(|n| Svf::new(n.cutoff, n.q, sr)),
+            noise_amp: noise.map_or(0.0, |n| n.level),
+            noise_coef: noise.map_or(0.0, |n| {
+                // Bursts decay quickly, only the last one rings out.
+                let decay = if n.bursts > 0 { 0.004 } else { n.decay };
+                decay_coef(decay, sr)
+            }),
+            bursts_left: noise.map_or(0, |n| n.bursts.saturating_sub(1)),
+            burst_timer: burst_interval(sr),
+
+            metal_phases: [0.0; 6],
+            metal_highpass: metal.map(|m| Svf::new(m.highpass, 0.7, sr)),
+            metal_bandpass: metal.map(|m| Svf::new(m.bandpass, 1.0, sr)),
+            metal_amp: metal.map_or(0.0, |m| m.level),
+            metal_coef: metal.map_or(0.0, |m| decay_coef(m.decay, sr)),
+            hat_choke_ctr: synth.hat_choke_ctr,
+        }
+    }
+
+    fn step_frame(&mut self, synth: &DrumSynth) -> (f32, f32) {
+        let patch = match &self.patch {
+            Some(p) => p,
+            None => return (0.0, 0.0),
+        };
+        let sr = synth.sample_rate;
+        let mut val = 0.0;
+
+        if let (Some(state), Some(tone)) = (&mut self.tone, &patch.tone) {
+            val += state.step(tone, sr);
+        }
+        if let (Some(state), Some(tone)) = (&mut self.overtone, &patch.overtone) {
+            val += state.step(tone, sr);
+        }
+
+        if let (Some(filter), Some(noise)) = (&mut self.noise_filter, &patch.noise) {
+            if self.bursts_left > 0 {
+                self.burst_timer -= 1;
+                if self.burst_timer == 0 {
+                    self.bursts_left -= 1;
+                    self.burst_timer = burst_interval(sr);
+                    self.noise_amp = noise.level;
+                    if self.bursts_left == 0 {
+                        self.noise_coef = decay_coef(noise.decay, sr);
+                    }
+                }
+            }
+            let white = 2.0 * self.rng.next_float() - 1.0;
+            val += filter.process(white, noise.mode) * self.noise_amp;
+            self.noise_amp = (self.noise_amp * self.noise_coef).flush_denormal();
+        }
+
+        if let Some(metal) = &patch.metal {
+            if patch.choke == Choke::ChokedByHat && self.hat_choke_ctr != synth.hat_choke_ctr {
+                self.metal_coef = decay_coef(CHOKE_TIME, sr);
+            }
+
+            let mut cluster = 0.0;
+            for (phase, freq) in self.metal_phases.iter_mut().zip(metal.partials) {
+                cluster += if *phase < 0.5 { 1.0 } else { -1.0 };
+                *phase = (*phase + freq / sr).fract();
+            }
+            cluster /= metal.partials.len() as f32;
+
+            let hp = self.metal_highpass.as_mut().unwrap();
+            let bp = self.metal_bandpass.as_mut().unwrap();
+            let filtered = bp.process(
+                hp.process(cluster, FilterMode::HighPass),
+                FilterMode::BandPass,
+            );
+            val += filtered * self.metal_amp;
+            self.metal_amp = (self.metal_amp * self.metal_coef).flush_denormal();
+        }
+
+        let val = val * self.gain * synth.master_volume.get();
+        (val, val)
+    }
+
+    // Drums are one-shot, they always play out.
+    fn notify_release(&mut self) {}
+
+    fn is_done(&self, _synth: &DrumSynth) -> bool {
+        let amp = |s: &Option<ToneState>| s.as_ref().map_or(0.0, |s| s.amp);
+        self.patch.is_none()
+            || (self.bursts_left == 0
+                && amp(&self.tone) < SILENCE
+                && amp(&self.overtone) < SILENCE
+                && self.noise_amp < SILENCE
+                && self.metal_amp < SILENCE)
+    }
+}
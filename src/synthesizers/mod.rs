@@ -1 +1,102 @@
 pub mod default;
+pub mod drums;
+pub mod sampler;
+pub mod soundfont;
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::mpsc;
+
+use crate::params::ParamInfo;
+use crate::synth_controller::{Part, SynthEvent};
+
+/// All available synthesizer engines. New engines only need to be added here.
+pub const ENGINES: &[Engine] = &[
+    default::ENGINE,
+    sampler::ENGINE,
+    soundfont::ENGINE,
+    drums::ENGINE,
+];
+
+pub fn find_engine(name: &str) -> Result<&'static Engine> {
+    ENGINES.iter().find(|e| e.name == name).ok_or_else(|| {
+        let names: Vec<_> = ENGINES.iter().map(|e| e.name).collect();
+        anyhow!("unknown engine {}, available: {}", name, names.join(", "))
+    })
+}
+
+type BuildFn = fn(&EngineConfig, mpsc::Receiver<SynthEvent>) -> Result<Box<dyn Part>>;
+
+/// An engine-specific option, given on the command line as `name=value`.
+pub struct EngineOption {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+/// Describes a synthesizer engine and how to build it.
+pub struct Engine {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub options: &'static [EngineOption],
+    pub params: &'static [ParamInfo],
+    pub build: BuildFn,
+}
+
+impl Engine {
+    pub fn start(
+        &self,
+        config: &EngineConfig,
+        event_queue: mpsc::Receiver<SynthEvent>,
+    ) -> Result<Box<dyn Part>> {
+        for name in config.options.keys() {
+            if !self.options.iter().any(|o| o.name == name) {
+                bail!("engine {} has no option {}", self.name, name);
+            }
+        }
+        (self.build)(config, event_queue)
+    }
+}
+
+pub struct EngineConfig {
+    pub sample_rate: f32,
+    options: HashMap<String, String>,
+}
+
+impl EngineConfig {
+    /// Parses options of the form `name=value`.
+    pub fn new(sample_rate: f32, options: &[String]) -> Result<Self> {
+        let options = options
+            .iter()
+            .map(|opt| match opt.split_once('=') {
+                Some((name, value)) => Ok((name.trim().to_owned(), value.trim().to_owned())),
+                None => Err(anyhow!(
+                    "engine option {} is not of the form name=value",
+                    opt
+                )),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            sample_rate,
+            options,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|s| s.as_str())
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str> {
+        self.get(name)
+            .ok_or_else(|| anyhow!("missing engine option {}", name))
+    }
+
+    pub fn parse_or<T: FromStr>(&self, name: &str, default: T) -> Result<T> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow!("invalid value {} for engine option {}", value, name)),
+            None => Ok(default),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::util::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// Linear attack, exponential decay and release ADSR envelope.
+#[derive(Debug, Clone)]
+pub struct Adsr {
+    stage: Stage,
+    level: f32,
+    attack_step: f32,
+    decay_coef: f32,
+    sustain: f32,
+    release_coef: f32,
+}
+
+// Exponential segments are considered finished at -80 db.
+const SILENCE: f32 = 1e-4;
+
+impl Adsr {
+    /// Times in seconds, sustain as a fraction of the peak level.
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> Self {
+        let coef = |time: f32| SILENCE.powf(1.0 / (time.max(0.001) * sample_rate));
+        Self {
+            stage: Stage::Attack,
+            level: 0.0,
+            attack_step: 1.0 / (attack.max(0.0005) * sample_rate),
+            decay_coef: coef(decay),
+            sustain: sustain.clamp(0.0, 1.0),
+            release_coef: coef(release),
+        }
+    }
+
+    pub fn release(&mut self) {
+        if self.stage != Stage::Done {
+            self.stage = Stage::Release;
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    pub fn step(&mut self) -> f32 {
+        match self.stage {
+            Stage::Attack => {
+                self.level += self.attack_step;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+
+            Stage::Decay => {
+                // Decay towards zero but stop at the sustain level, so the
+                // decay time is independent of the sustain level.
+                self.level *= self.decay_coef;
+                if self.level <= self.sustain.max(SILENCE) {
+                    self.level = self.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+
+            Stage::Sustain => {
+                if self.level <= 0.0 {
+                    self.stage = Stage::Done;
+                }
+            }
+
+            Stage::Release => {
+                self.level = (self.level * self.release_coef).flush_denormal();
+                if self.level < SILENCE {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            }
+
+            Stage::Done => {}
+        }
+        self.level
+    }
+}
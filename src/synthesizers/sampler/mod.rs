@@ -0,0 +1,298 @@
+mod envelope;
+mod sfz;
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::{Engine, EngineOption};
+use crate::params::{ParamId, ParamInfo, Smoothed, Unit};
+use crate::synth::{Synth, Voice};
+use crate::synth_controller::{Part, SynthPart};
+use crate::util::*;
+use crate::wav::read_wav;
+use envelope::Adsr;
+use sfz::{LoopMode, Region};
+
+/// How many regions a single note may trigger at once.
+const MAX_LAYERS: usize = 4;
+
+declare_params!(enum Param, PARAMS {
+    MasterVolume => ParamInfo::linear("master_volume", 0.0, 1.0, 1.0, Unit::Percent)
+        .with_smoothing(10.0),
+});
+
+struct SampleData {
+    channels: usize,
+    sample_rate: f32,
+    /// Interleaved frames.
+    data: Vec<f32>,
+}
+
+impl SampleData {
+    fn len(&self) -> usize {
+        self.data.len() / self.channels
+    }
+
+    #[inline(always)]
+    fn frame(&self, idx: usize) -> (f32, f32) {
+        let i = idx * self.channels;
+        if self.channels == 1 {
+            (self.data[i], self.data[i])
+        } else {
+            (self.data[i], self.data[i + 1])
+        }
+    }
+}
+
+struct LoadedRegion {
+    region: Region,
+    sample: usize,
+    loop_mode: LoopMode,
+    loop_start: usize,
+    loop_end: usize,
+}
+
+pub const ENGINE: Engine = Engine {
+    name: "sampler",
+    description: "Multisample instrument playing WAV files mapped by an SFZ definition.",
+    options: &[EngineOption {
+        name: "sfz",
+        help: "The SFZ file defining the instrument (required).",
+    }],
+    params: PARAMS,
+    build: |config, event_queue| -> Result<Box<dyn Part>> {
+        let synth = SamplerSynth::new(config.require("sfz")?, config.sample_rate)?;
+        Ok(Box::new(SynthPart::new(synth, event_queue)))
+    },
+};
+
+/// Multisample instrument playing WAV files mapped by an SFZ definition.
+pub struct SamplerSynth {
+    sample_rate: f32,
+    regions: Vec<LoadedRegion>,
+    samples: Vec<SampleData>,
+
+    master_volume: Smoothed,
+}
+
+impl SamplerSynth {
+    pub fn new(sfz_file: &str, sample_rate: f32) -> Result<Self> {
+        let mut samples = Vec::new();
+        let mut sample_indices: HashMap<PathBuf, usize> = HashMap::new();
+        let mut regions = Vec::new();
+        for region in sfz::parse(sfz_file)? {
+            let sample = match sample_indices.get(&region.sample) {
+                Some(&idx) => idx,
+                None => {
+                    let (spec, data) = read_wav(&region.sample)?;
+                    if spec.channels == 0 || spec.channels > 2 {
+                        bail!("{:?} must be mono or stereo", region.sample);
+                    }
+                    samples.push(SampleData {
+                        channels: spec.channels as usize,
+                        sample_rate: spec.sample_rate as f32,
+                        data,
+                    });
+                    sample_indices.insert(region.sample.clone(), samples.len() - 1);
+                    samples.len() - 1
+                }
+            };
+
+            let len = samples[sample].len();
+            if len < 2 {
+                bail!("{:?} is too short", region.sample);
+            }
+
+            // Like most players, loop the whole sample if a loop mode is given
+            // without loop points.
+            let loop_end = region.loop_end.map_or(len, |e| (e + 1).min(len));
+            let loop_start = region.loop_start.unwrap_or(0).min(loop_end - 1);
+            let loop_mode = region.loop_mode.unwrap_or(LoopMode::NoLoop);
+            regions.push(LoadedRegion {
+                region,
+                sample,
+                loop_mode,
+                loop_start,
+                loop_end,
+            });
+        }
+
+        Ok(Self {
+            sample_rate,
+            regions,
+            samples,
+            master_volume: Param::MasterVolume.info().smoothed(sample_rate),
+        })
+    }
+}
+
+impl Synth for SamplerSynth {
+    type Voice = SamplerVoice;
+
+    fn set_param(&mut self, param: ParamId, value: f32) {
+        if Param::from_id(param) == Some(Param::MasterVolume) {
+            self.master_volume.set_target(value);
+        }
+    }
+
+    fn notify_buffer(&mut self) {}
+
+    fn step_frame(&mut self) {
+        self.master_volume.step();
+    }
+
+    fn process_master(&mut self, l: f32, r: f32) -> (f32, f32) {
+        (l, r)
+    }
+}
+
+struct Layer {
+    region: usize,
+    pos: f64,
+    step: f64,
+    gain: f32,
+    looping: bool,
+    one_shot: bool,
+    sustain_loop: bool,
+    ended: bool,
+    envelope: Adsr,
+}
+
+impl Layer {
+    /// Reads the sample at a fractional position using 4-point, 3rd-order
+    /// Hermite interpolation, wrapping around the loop while looping.
+    #[inline(always)]
+    fn read(&self, sample: &SampleData, loaded: &LoadedRegion) -> (f32, f32) {
+        let idx = self.pos as usize;
+        let frac = (self.pos - idx as f64) as f32;
+        let len = sample.len();
+
+        let fetch = |i: isize| -> (f32, f32) {
+            let mut i = i;
+            if self.looping && i >= loaded.loop_end as isize {
+                let loop_start = loaded.loop_start as isize;
+                let loop_len = (loaded.loop_end - loaded.loop_start) as isize;
+                i = loop_start + (i - loop_start).rem_euclid(loop_len);
+            }
+            if i < 0 || i as usize >= len {
+                (0.0, 0.0)
+            } else {
+                sample.frame(i as usize)
+            }
+        };
+
+        let i = idx as isize;
+        let (xm1, x0, x1, x2) = (fetch(i - 1), fetch(i), fetch(i + 1), fetch(i + 2));
+        (
+            hermite(frac, xm1.0, x0.0, x1.0, x2.0),
+            hermite(frac, xm1.1, x0.1, x1.1, x2.1),
+        )
+    }
+}
+
+#[inline(always)]
+fn hermite(t: f32, xm1: f32, x0: f32, x1: f32, x2: f32) -> f32 {
+    let c1 = 0.5 * (x1 - xm1);
+    let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+    let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+    ((c3 * t + c2) * t + c1) * t + x0
+}
+
+pub struct SamplerVoice {
+    layers: [Option<Layer>; MAX_LAYERS],
+}
+
+impl Voice<SamplerSynth> for SamplerVoice {
+    fn new(pitch: f32, vel: f32, synth: &mut SamplerSynth) -> Self {
+        let key = (69.0 + 12.0 * (pitch / 440.0).log2()).round() as i32;
+        let vel127 = (vel * 127.0).round() as u8;
+
+        let mut layers: [Option<Layer>; MAX_LAYERS] = Default::default();
+        let matching = synth
+            .regions
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.region.matches(key.clamp(0, 127) as u8, vel127));
+        for (slot, (idx, loaded)) in layers.iter_mut().zip(matching) {
+            let region = &loaded.region;
+            let sample = &synth.samples[loaded.sample];
+
+            let semitones = (key + region.transpose - region.pitch_keycenter as i32) as f64
+                + region.tune as f64 / 100.0;
+            let step =
+                (semitones / 12.0).exp2() * sample.sample_rate as f64 / synth.sample_rate as f64;
+
+            *slot = Some(Layer {
+                region: idx,
+                pos: region.offset.min(sample.len() - 1) as f64,
+                step,
+                gain: vel * vel * region.volume.db_to_gain(),
+                looping: matches!(loaded.loop_mode, LoopMode::Continuous | LoopMode::Sustain),
+                one_shot: loaded.loop_mode == LoopMode::OneShot,
+                sustain_loop: loaded.loop_mode == LoopMode::Sustain,
+                ended: false,
+                envelope: Adsr::new(
+                    region.ampeg_attack,
+                    region.ampeg_decay,
+                    region.ampeg_sustain,
+                    region.ampeg_release,
+                    synth.sample_rate,
+                ),
+            });
+        }
+
+        Self { layers }
+    }
+
+    fn step_frame(&mut self, synth: &SamplerSynth) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for layer in self.layers.iter_mut().flatten() {
+            if layer.ended {
+                continue;
+            }
+
+            let loaded = &synth.regions[layer.region];
+            let sample = &synth.samples[loaded.sample];
+            let (l, r) = layer.read(sample, loaded);
+            let gain = layer.gain * layer.envelope.step() * synth.master_volume.get();
+            left += l * gain;
+            right += r * gain;
+
+            layer.pos += layer.step;
+            if layer.looping && layer.pos >= loaded.loop_end as f64 {
+                // A short loop played high can step past it more than once.
+                let loop_start = loaded.loop_start as f64;
+                let loop_len = (loaded.loop_end - loaded.loop_start) as f64;
+                layer.pos = loop_start + (layer.pos - loop_start).rem_euclid(loop_len);
+            } else if layer.pos >= sample.len() as f64 {
+                layer.ended = true;
+            }
+            // Checked apart from the loop, which may wrap on every frame.
+            if layer.envelope.is_done() {
+                layer.ended = true;
+            }
+        }
+
+        (left, right)
+    }
+
+    fn notify_release(&mut self) {
+        for layer in self.layers.iter_mut().flatten() {
+            // One-shot samples always play to the end.
+            if layer.one_shot {
+                continue;
+            }
+            if layer.sustain_loop {
+                layer.looping = false;
+            }
+            layer.envelope.release();
+        }
+    }
+
+    fn is_done(&self, _synth: &SamplerSynth) -> bool {
+        self.layers.iter().flatten().all(|layer| layer.ended)
+    }
+}
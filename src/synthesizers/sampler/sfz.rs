@@ -0,0 +1,254 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoopMode {
+    NoLoop,
+    OneShot,
+    Continuous,
+    Sustain,
+}
+
+/// A single region of an SFZ file, with inherited `<global>` and `<group>`
+/// opcodes already applied.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub sample: PathBuf,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    pub pitch_keycenter: u8,
+    /// Fine tuning in cents.
+    pub tune: f32,
+    pub transpose: i32,
+    /// Gain in db.
+    pub volume: f32,
+    pub offset: usize,
+    pub loop_mode: Option<LoopMode>,
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    /// Envelope times in seconds, sustain as a fraction.
+    pub ampeg_attack: f32,
+    pub ampeg_decay: f32,
+    pub ampeg_sustain: f32,
+    pub ampeg_release: f32,
+}
+
+impl Region {
+    pub fn matches(&self, key: u8, vel: u8) -> bool {
+        (self.lokey..=self.hikey).contains(&key) && (self.lovel..=self.hivel).contains(&vel)
+    }
+
+    fn from_opcodes(opcodes: &HashMap<String, String>, base_dir: &Path) -> Result<Self> {
+        let get = |name: &str| opcodes.get(name).map(|s| s.as_str());
+        let num = |name: &str, default: f32| -> Result<f32> {
+            get(name).map_or(Ok(default), |v| {
+                v.parse()
+                    .map_err(|_| anyhow!("invalid value {} for opcode {}", v, name))
+            })
+        };
+        let opt_num =
+            |name: &str| -> Result<Option<f32>> { get(name).map(|_| num(name, 0.0)).transpose() };
+        let key =
+            |name: &str, default: u8| -> Result<u8> { get(name).map_or(Ok(default), parse_key) };
+
+        let sample = get("sample").ok_or_else(|| anyhow!("region without sample"))?;
+        let default_path = get("default_path").unwrap_or("");
+        let sample = base_dir.join(format!("{}{}", default_path, sample).replace('\\', "/"));
+
+        let (lokey, hikey, keycenter) = match get("key") {
+            Some(k) => {
+                let k = parse_key(k)?;
+                (k, k, k)
+            }
+            None => (
+                key("lokey", 0)?,
+                key("hikey", 127)?,
+                key("pitch_keycenter", 60)?,
+            ),
+        };
+
+        let loop_mode = match get("loop_mode") {
+            None => None,
+            Some("no_loop") => Some(LoopMode::NoLoop),
+            Some("one_shot") => Some(LoopMode::OneShot),
+            Some("loop_continuous") => Some(LoopMode::Continuous),
+            Some("loop_sustain") => Some(LoopMode::Sustain),
+            Some(other) => bail!("unknown loop_mode {}", other),
+        };
+
+        Ok(Self {
+            sample,
+            lokey,
+            hikey,
+            lovel: num("lovel", 0.0)? as u8,
+            hivel: num("hivel", 127.0)? as u8,
+            pitch_keycenter: key("pitch_keycenter", keycenter)?,
+            tune: num("tune", 0.0)?,
+            transpose: num("transpose", 0.0)? as i32,
+            volume: num("volume", 0.0)?,
+            offset: num("offset", 0.0)? as usize,
+            loop_mode,
+            loop_start: opt_num("loop_start")?.map(|v| v as usize),
+            loop_end: opt_num("loop_end")?.map(|v| v as usize),
+            ampeg_attack: num("ampeg_attack", 0.0)?,
+            ampeg_decay: num("ampeg_decay", 0.0)?,
+            ampeg_sustain: num("ampeg_sustain", 100.0)? / 100.0,
+            ampeg_release: num("ampeg_release", 0.001)?,
+        })
+    }
+}
+
+/// Parses a MIDI key given as a number or a note name such as `c#4` (where
+/// `c4` is 60).
+fn parse_key(s: &str) -> Result<u8> {
+    if let Ok(n) = s.parse::<u8>() {
+        return Ok(n);
+    }
+
+    let lower = s.to_lowercase();
+    let mut chars = lower.chars().peekable();
+    let base = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => bail!("invalid key {}", s),
+    };
+    let accidental = match chars.peek() {
+        Some('#') => 1,
+        Some('b') => -1,
+        _ => 0,
+    };
+    if accidental != 0 {
+        chars.next();
+    }
+    let octave: i32 = chars
+        .collect::<String>()
+        .parse()
+        .map_err(|_| anyhow!("invalid key {}", s))?;
+    let key = (octave + 1) * 12 + base + accidental;
+    if !(0..128).contains(&key) {
+        bail!("key {} out of range", s);
+    }
+    Ok(key as u8)
+}
+
+/// Parses the regions from an SFZ file. Supports the `<control>`, `<global>`,
+/// `<group>` and `<region>` headers and a subset of the opcodes.
+pub fn parse(fname: &str) -> Result<Vec<Region>> {
+    let text = fs::read_to_string(fname)?;
+    let base_dir = Path::new(fname).parent().unwrap_or_else(|| Path::new(""));
+
+    #[derive(PartialEq)]
+    enum Header {
+        None,
+        Control,
+        Global,
+        Group,
+        Region,
+    }
+
+    let mut header = Header::None;
+    let mut control = HashMap::new();
+    let mut global = HashMap::new();
+    let mut group = HashMap::new();
+    let mut region = HashMap::new();
+    let mut regions = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or("");
+        let mut last_opcode: Option<String> = None;
+
+        for token in tokenize(line) {
+            if token.starts_with('<') && token.ends_with('>') {
+                let new_header = match token {
+                    "<control>" => Header::Control,
+                    "<global>" => Header::Global,
+                    "<group>" => Header::Group,
+                    "<region>" => Header::Region,
+                    _ => bail!("unsupported SFZ header {}", token),
+                };
+                if header == Header::Region {
+                    let opcodes = merge_opcodes(&[&control, &global, &group, &region]);
+                    regions.push(Region::from_opcodes(&opcodes, base_dir)?);
+                    region.clear();
+                }
+                if new_header == Header::Group {
+                    group.clear();
+                }
+                header = new_header;
+                last_opcode = None;
+            } else if let Some((name, value)) = token.split_once('=') {
+                let target = match header {
+                    Header::Control => &mut control,
+                    Header::Global => &mut global,
+                    Header::Group => &mut group,
+                    Header::Region => &mut region,
+                    Header::None => bail!("opcode {} outside of a header", name),
+                };
+                target.insert(name.to_string(), value.to_string());
+                last_opcode = Some(name.to_string());
+            } else if let Some(name) = &last_opcode {
+                // Values such as sample paths may contain spaces.
+                let target = match header {
+                    Header::Control => &mut control,
+                    Header::Global => &mut global,
+                    Header::Group => &mut group,
+                    _ => &mut region,
+                };
+                if let Some(value) = target.get_mut(name) {
+                    value.push(' ');
+                    value.push_str(token);
+                }
+            } else {
+                bail!("unexpected token {} in SFZ file", token);
+            }
+        }
+    }
+    if header == Header::Region {
+        let opcodes = merge_opcodes(&[&control, &global, &group, &region]);
+        regions.push(Region::from_opcodes(&opcodes, base_dir)?);
+    }
+
+    Ok(regions)
+}
+
+/// Splits a line into whitespace separated words, with headers split off as
+/// tokens of their own since they needn't be followed by a space, as in
+/// `<region>sample=a.wav`.
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    for mut word in line.split_whitespace() {
+        while let Some(start) = word.find('<') {
+            let end = match word[start..].find('>') {
+                Some(len) => start + len + 1,
+                None => break,
+            };
+            if start > 0 {
+                tokens.push(&word[..start]);
+            }
+            tokens.push(&word[start..end]);
+            word = &word[end..];
+        }
+        if !word.is_empty() {
+            tokens.push(word);
+        }
+    }
+    tokens
+}
+
+/// Merges opcode sets, later sets overriding earlier ones.
+fn merge_opcodes(sets: &[&HashMap<String, String>]) -> HashMap<String, String> {
+    let mut merged = HashMap::new();
+    for set in sets {
+        merged.extend(set.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    merged
+}
@@ -0,0 +1,131 @@
+use crate::util::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+/// The level at which the envelope is considered silent, in db.
+const FLOOR_DB: f32 = -100.0;
+
+/// SoundFont volume envelope. The attack is linear in amplitude, decay and
+/// release are linear in db and their times are for a full 100 db change.
+#[derive(Debug, Clone)]
+pub struct VolumeEnvelope {
+    stage: Stage,
+    samples_left: f32,
+
+    attack_samples: f32,
+    hold_samples: f32,
+    decay_db_per_sample: f32,
+    sustain_db: f32,
+    release_db_per_sample: f32,
+
+    // Linear level during the attack, db level afterwards.
+    amplitude: f32,
+    level_db: f32,
+}
+
+impl VolumeEnvelope {
+    /// Times in seconds, sustain as attenuation in db.
+    pub fn new(
+        delay: f32,
+        attack: f32,
+        hold: f32,
+        decay: f32,
+        sustain_db: f32,
+        release: f32,
+        sample_rate: f32,
+    ) -> Self {
+        let per_sample = |time: f32| -FLOOR_DB / (time.max(0.001) * sample_rate);
+        Self {
+            stage: Stage::Delay,
+            samples_left: delay * sample_rate,
+
+            attack_samples: attack * sample_rate,
+            hold_samples: hold * sample_rate,
+            decay_db_per_sample: per_sample(decay),
+            sustain_db: -sustain_db.clamp(0.0, -FLOOR_DB),
+            release_db_per_sample: per_sample(release),
+
+            amplitude: 0.0,
+            level_db: FLOOR_DB,
+        }
+    }
+
+    pub fn release(&mut self) {
+        match self.stage {
+            Stage::Done | Stage::Release => {}
+            Stage::Delay | Stage::Attack => {
+                self.level_db = self.amplitude.max(1e-5).gain_to_db();
+                self.stage = Stage::Release;
+            }
+            _ => self.stage = Stage::Release,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    pub fn step(&mut self) -> f32 {
+        match self.stage {
+            Stage::Delay => {
+                self.samples_left -= 1.0;
+                if self.samples_left <= 0.0 {
+                    self.stage = Stage::Attack;
+                    self.samples_left = self.attack_samples;
+                }
+                return 0.0;
+            }
+
+            Stage::Attack => {
+                self.samples_left -= 1.0;
+                self.amplitude = 1.0 - (self.samples_left / self.attack_samples.max(1.0)).max(0.0);
+                if self.samples_left <= 0.0 {
+                    self.stage = Stage::Hold;
+                    self.samples_left = self.hold_samples;
+                    self.level_db = 0.0;
+                }
+                return self.amplitude;
+            }
+
+            Stage::Hold => {
+                self.samples_left -= 1.0;
+                if self.samples_left <= 0.0 {
+                    self.stage = Stage::Decay;
+                }
+            }
+
+            Stage::Decay => {
+                self.level_db -= self.decay_db_per_sample;
+                if self.level_db <= self.sustain_db {
+                    self.level_db = self.sustain_db;
+                    self.stage = Stage::Sustain;
+                }
+            }
+
+            Stage::Sustain => {
+                if self.level_db <= FLOOR_DB {
+                    self.stage = Stage::Done;
+                }
+            }
+
+            Stage::Release => {
+                self.level_db -= self.release_db_per_sample;
+                if self.level_db <= FLOOR_DB {
+                    self.stage = Stage::Done;
+                }
+            }
+
+            Stage::Done => return 0.0,
+        }
+        self.level_db.db_to_gain()
+    }
+}
@@ -0,0 +1,534 @@
+mod envelope;
+mod sf2;
+
+use anyhow::{anyhow, Result};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use super::{Engine, EngineOption};
+use crate::params::{ParamId, ParamInfo, Smoothed, Unit};
+use crate::synth::{Synth, Voice};
+use crate::synth_controller::{Part, SynthPart};
+use crate::util::*;
+use envelope::VolumeEnvelope;
+use sf2::*;
+
+/// How many zones a single note may trigger at once.
+const MAX_LAYERS: usize = 8;
+
+// Generators that are meaningless at preset level and must be ignored there.
+const INSTRUMENT_ONLY_GENERATORS: [usize; 14] = [
+    GEN_START_ADDRS_OFFSET,
+    GEN_END_ADDRS_OFFSET,
+    GEN_STARTLOOP_ADDRS_OFFSET,
+    GEN_ENDLOOP_ADDRS_OFFSET,
+    GEN_START_ADDRS_COARSE_OFFSET,
+    GEN_END_ADDRS_COARSE_OFFSET,
+    GEN_STARTLOOP_ADDRS_COARSE_OFFSET,
+    GEN_ENDLOOP_ADDRS_COARSE_OFFSET,
+    GEN_KEYNUM,
+    GEN_VELOCITY,
+    GEN_SAMPLE_MODES,
+    GEN_OVERRIDING_ROOT_KEY,
+    GEN_SAMPLE_ID,
+    GEN_INSTRUMENT,
+];
+
+declare_params!(enum Param, PARAMS {
+    MasterVolume => ParamInfo::linear("master_volume", 0.0, 1.0, 1.0, Unit::Percent)
+        .with_smoothing(10.0),
+});
+
+pub const ENGINE: Engine = Engine {
+    name: "soundfont",
+    description: "Plays SoundFont 2 presets, program changes select the preset.",
+    options: &[
+        EngineOption {
+            name: "sf2",
+            help: "The SoundFont 2 file (required).",
+        },
+        EngineOption {
+            name: "bank",
+            help: "The bank to select presets from, defaults to 0.",
+        },
+        EngineOption {
+            name: "program",
+            help: "The initial preset, defaults to 0.",
+        },
+    ],
+    params: PARAMS,
+    build: |config, event_queue| -> Result<Box<dyn Part>> {
+        let synth = SoundFontSynth::new(
+            config.require("sf2")?,
+            config.parse_or("bank", 0)?,
+            config.parse_or("program", 0)?,
+            config.sample_rate,
+        )?;
+        Ok(Box::new(SynthPart::new(synth, event_queue)))
+    },
+};
+
+/// Plays presets from a SoundFont 2 file, selected with program changes.
+pub struct SoundFontSynth {
+    sample_rate: f32,
+    font: SoundFont,
+    bank: u16,
+    preset: Option<usize>,
+    missing_program: Arc<AtomicU32>,
+
+    master_volume: Smoothed,
+}
+
+impl SoundFontSynth {
+    pub fn new(sf2_file: &str, bank: u16, program: u16, sample_rate: f32) -> Result<Self> {
+        let font = SoundFont::load(sf2_file)?;
+        let preset = font
+            .find_preset(bank, program)
+            .ok_or_else(|| anyhow!("no preset {}:{} in {}", bank, program, sf2_file))?;
+
+        Ok(Self {
+            sample_rate,
+            font,
+            bank,
+            preset: Some(preset),
+            missing_program: Arc::new(AtomicU32::new(0)),
+            master_volume: Param::MasterVolume.info().smoothed(sample_rate),
+        })
+    }
+}
+
+impl Synth for SoundFontSynth {
+    type Voice = SoundFontVoice;
+
+    fn set_param(&mut self, param: ParamId, value: f32) {
+        if Param::from_id(param) == Some(Param::MasterVolume) {
+            self.master_volume.set_target(value);
+        }
+    }
+
+    fn program_change(&mut self, program: u8) {
+        self.preset = self.font.find_preset(self.bank, program as u16);
+        if self.preset.is_none() {
+            // Printing could block the audio thread, leave it to the main loop.
+            self.missing_program
+                .store(program as u32 + 1, Ordering::Relaxed);
+        }
+    }
+
+    fn missing_program(&self) -> Option<Arc<AtomicU32>> {
+        Some(self.missing_program.clone())
+    }
+
+    fn notify_buffer(&mut self) {}
+
+    fn step_frame(&mut self) {
+        self.master_volume.step();
+    }
+
+    fn process_master(&mut self, l: f32, r: f32) -> (f32, f32) {
+        (l, r)
+    }
+}
+
+/// Maps a note-on source value in [0, 1] through a modulator source's
+/// direction, polarity and curve type.
+fn mod_source_curve(src: u16, x: f32) -> f32 {
+    let x = if src & 0x0100 != 0 { 1.0 - x } else { x };
+    let bipolar = src & 0x0200 != 0;
+    let concave = |x: f32| (-40.0 / 96.0 * (1.0 - x).max(1e-6).log10()).clamp(0.0, 1.0);
+    let y = match src >> 10 {
+        1 => concave(x),
+        2 => 1.0 - concave(1.0 - x),
+        3 => {
+            if x >= 0.5 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        _ => x,
+    };
+    if bipolar {
+        2.0 * y - 1.0
+    } else {
+        y
+    }
+}
+
+/// The value of a modulator source for a note, None for sources we can't
+/// evaluate at note-on.
+fn mod_source(src: u16, key: u8, vel: u8) -> Option<f32> {
+    // Only general controllers, MIDI CC sources aren't tracked.
+    if src & 0x0080 != 0 {
+        return None;
+    }
+    let raw = match src & 0x7f {
+        0 => return Some(1.0),
+        2 => vel as f32 / 128.0,
+        3 => key as f32 / 128.0,
+        _ => return None,
+    };
+    Some(mod_source_curve(src, raw))
+}
+
+fn modulator_value(m: &Modulator, key: u8, vel: u8) -> f32 {
+    // A primary source of 'no controller' means the modulator does nothing.
+    if m.src & 0x7f == 0 && m.src & 0x0080 == 0 {
+        return 0.0;
+    }
+    let primary = mod_source(m.src, key, vel).unwrap_or(0.0);
+    let secondary = mod_source(m.amount_src, key, vel).unwrap_or(0.0);
+    let v = m.amount as f32 * primary * secondary;
+    if m.transform == 2 {
+        v.abs()
+    } else {
+        v
+    }
+}
+
+/// Applies modulators to the generator values. Later modulators of the same
+/// kind replace earlier ones, as with local zones overriding global zones.
+fn apply_modulators<'a>(
+    gens: &mut [f32; NUM_GENERATORS],
+    sets: impl Iterator<Item = &'a [Modulator]>,
+    key: u8,
+    vel: u8,
+) {
+    let mut active: Vec<Modulator> = Vec::new();
+    for set in sets {
+        for m in set {
+            match active.iter_mut().find(|a| a.same_kind(m)) {
+                Some(a) => *a = *m,
+                None => active.push(*m),
+            }
+        }
+    }
+
+    for m in &active {
+        // Links to other modulators (bit 15) aren't supported.
+        if (m.dest as usize) < NUM_GENERATORS {
+            gens[m.dest as usize] += modulator_value(m, key, vel);
+        }
+    }
+}
+
+fn timecents_to_secs(tc: f32) -> f32 {
+    (tc / 1200.0).exp2()
+}
+
+/// Two-pole resonant lowpass (RBJ cookbook biquad).
+#[derive(Debug, Clone)]
+struct LowPass {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl LowPass {
+    fn new(cutoff: f32, q: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff.min(0.45 * sample_rate) / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = (self.b1 * x - self.a1 * y + self.z2).flush_denormal();
+        self.z2 = (self.b2 * x - self.a2 * y).flush_denormal();
+        y
+    }
+}
+
+struct Layer {
+    pos: f64,
+    step: f64,
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    looping: bool,
+    sustain_loop: bool,
+    gain_l: f32,
+    gain_r: f32,
+    filter: Option<LowPass>,
+    envelope: VolumeEnvelope,
+    ended: bool,
+}
+
+impl Layer {
+    fn new(
+        synth: &SoundFontSynth,
+        preset: &Preset,
+        pzone: &Zone,
+        instrument: &Instrument,
+        izone: &Zone,
+        key: u8,
+        vel: u8,
+    ) -> Self {
+        let font = &synth.font;
+        let iglobal = instrument.global_zone.as_ref();
+        let pglobal = preset.global_zone.as_ref();
+
+        let mut gens = [0.0f32; NUM_GENERATORS];
+        for (g, value) in gens.iter_mut().enumerate() {
+            let inst = izone
+                .generators
+                .get(g)
+                .or_else(|| iglobal.and_then(|z| z.generators.get(g)))
+                .unwrap_or_else(|| default_generator(g));
+            let preset_offset = if INSTRUMENT_ONLY_GENERATORS.contains(&g)
+                || g == GEN_KEY_RANGE
+                || g == GEN_VEL_RANGE
+            {
+                0
+            } else {
+                pzone
+                    .generators
+                    .get(g)
+                    .or_else(|| pglobal.and_then(|z| z.generators.get(g)))
+                    .unwrap_or(0)
+            };
+            *value = inst as f32 + preset_offset as f32;
+        }
+
+        let key = if gens[GEN_KEYNUM] >= 0.0 {
+            gens[GEN_KEYNUM] as u8
+        } else {
+            key
+        };
+        let vel = if gens[GEN_VELOCITY] >= 0.0 {
+            gens[GEN_VELOCITY] as u8
+        } else {
+            vel
+        };
+
+        // Instrument modulators override the defaults, preset modulators add
+        // to the result.
+        let empty: &[Modulator] = &[];
+        let inst_mods = [
+            &DEFAULT_MODULATORS[..],
+            iglobal.map_or(empty, |z| &z.modulators[..]),
+            &izone.modulators[..],
+        ];
+        apply_modulators(&mut gens, inst_mods.iter().copied(), key, vel);
+        let preset_mods = [
+            pglobal.map_or(empty, |z| &z.modulators[..]),
+            &pzone.modulators[..],
+        ];
+        apply_modulators(&mut gens, preset_mods.iter().copied(), key, vel);
+
+        let sample = &font.samples[izone.generators.get(GEN_SAMPLE_ID).unwrap() as u16 as usize];
+        let offset = |base: u32, fine: usize, coarse: usize| -> usize {
+            let pos = base as i64 + gens[fine] as i64 + 32768 * gens[coarse] as i64;
+            pos.clamp(0, font.sample_data.len() as i64) as usize
+        };
+        let start = offset(
+            sample.start,
+            GEN_START_ADDRS_OFFSET,
+            GEN_START_ADDRS_COARSE_OFFSET,
+        );
+        let end = offset(
+            sample.end,
+            GEN_END_ADDRS_OFFSET,
+            GEN_END_ADDRS_COARSE_OFFSET,
+        )
+        .max(start);
+        let loop_start = offset(
+            sample.start_loop,
+            GEN_STARTLOOP_ADDRS_OFFSET,
+            GEN_STARTLOOP_ADDRS_COARSE_OFFSET,
+        )
+        .clamp(start, end);
+        let loop_end = offset(
+            sample.end_loop,
+            GEN_ENDLOOP_ADDRS_OFFSET,
+            GEN_ENDLOOP_ADDRS_COARSE_OFFSET,
+        )
+        .clamp(loop_start, end);
+
+        let sample_modes = gens[GEN_SAMPLE_MODES] as i32 & 3;
+        let loop_valid = loop_end > loop_start + 1;
+
+        let root = if gens[GEN_OVERRIDING_ROOT_KEY] >= 0.0 {
+            gens[GEN_OVERRIDING_ROOT_KEY]
+        } else {
+            sample.original_pitch as f32
+        };
+        let semitones = (key as f32 - root) * gens[GEN_SCALE_TUNING] / 100.0
+            + gens[GEN_COARSE_TUNE]
+            + (gens[GEN_FINE_TUNE] + sample.pitch_correction as f32) / 100.0;
+        let step =
+            (semitones as f64 / 12.0).exp2() * sample.sample_rate as f64 / synth.sample_rate as f64;
+
+        let attenuation_db = gens[GEN_INITIAL_ATTENUATION].clamp(0.0, 1440.0) / 10.0;
+        let gain = (-attenuation_db).db_to_gain();
+        let pan = (gens[GEN_PAN] / 1000.0).clamp(-0.5, 0.5);
+        let pan_angle = (pan + 0.5) * PI / 2.0;
+
+        let fc = gens[GEN_INITIAL_FILTER_FC].clamp(1500.0, 13500.0);
+        let q_cb = gens[GEN_INITIAL_FILTER_Q].clamp(0.0, 960.0);
+        let filter = if fc >= 13500.0 && q_cb == 0.0 {
+            None
+        } else {
+            let cutoff = 8.176 * (fc / 1200.0).exp2();
+            let q = (q_cb / 10.0 - 3.01).db_to_gain();
+            Some(LowPass::new(cutoff, q, synth.sample_rate))
+        };
+
+        Self {
+            pos: start as f64,
+            step,
+            end,
+            loop_start,
+            loop_end,
+            looping: loop_valid && (sample_modes == 1 || sample_modes == 3),
+            sustain_loop: sample_modes == 3,
+            gain_l: gain * pan_angle.cos(),
+            gain_r: gain * pan_angle.sin(),
+            filter,
+            envelope: VolumeEnvelope::new(
+                timecents_to_secs(gens[GEN_DELAY_VOL_ENV]),
+                timecents_to_secs(gens[GEN_ATTACK_VOL_ENV]),
+                timecents_to_secs(gens[GEN_HOLD_VOL_ENV]),
+                timecents_to_secs(gens[GEN_DECAY_VOL_ENV]),
+                gens[GEN_SUSTAIN_VOL_ENV] / 10.0,
+                timecents_to_secs(gens[GEN_RELEASE_VOL_ENV]),
+                synth.sample_rate,
+            ),
+            ended: false,
+        }
+    }
+
+    /// 4-point, 3rd-order Hermite interpolation, wrapping around the loop
+    /// while looping.
+    #[inline(always)]
+    fn read(&self, data: &[f32]) -> f32 {
+        let idx = self.pos as usize;
+        let t = (self.pos - idx as f64) as f32;
+        let fetch = |i: usize| -> f32 {
+            let i = if self.looping && i >= self.loop_end {
+                self.loop_start + (i - self.loop_start) % (self.loop_end - self.loop_start)
+            } else {
+                i
+            };
+            if i < self.end {
+                data[i]
+            } else {
+                0.0
+            }
+        };
+
+        let xm1 = if idx > 0 { fetch(idx - 1) } else { 0.0 };
+        let (x0, x1, x2) = (fetch(idx), fetch(idx + 1), fetch(idx + 2));
+        let c1 = 0.5 * (x1 - xm1);
+        let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+        let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+        ((c3 * t + c2) * t + c1) * t + x0
+    }
+}
+
+pub struct SoundFontVoice {
+    layers: [Option<Layer>; MAX_LAYERS],
+}
+
+impl Voice<SoundFontSynth> for SoundFontVoice {
+    fn new(pitch: f32, vel: f32, synth: &mut SoundFontSynth) -> Self {
+        let mut layers: [Option<Layer>; MAX_LAYERS] = Default::default();
+        let preset = match synth.preset {
+            Some(p) => &synth.font.presets[p],
+            None => return Self { layers },
+        };
+
+        let key = (69.0 + 12.0 * (pitch / 440.0).log2())
+            .round()
+            .clamp(0.0, 127.0) as u8;
+        let vel = (vel * 127.0).round() as u8;
+
+        let pglobal = preset.global_zone.as_ref();
+        let mut slots = layers.iter_mut();
+        for pzone in preset.zones.iter().filter(|z| z.matches(pglobal, key, vel)) {
+            let instrument = &synth.font.instruments
+                [pzone.generators.get(GEN_INSTRUMENT).unwrap() as u16 as usize];
+            let iglobal = instrument.global_zone.as_ref();
+            for izone in instrument
+                .zones
+                .iter()
+                .filter(|z| z.matches(iglobal, key, vel))
+            {
+                match slots.next() {
+                    Some(slot) => {
+                        *slot = Some(Layer::new(
+                            synth, preset, pzone, instrument, izone, key, vel,
+                        ))
+                    }
+                    None => return Self { layers },
+                }
+            }
+        }
+
+        Self { layers }
+    }
+
+    fn step_frame(&mut self, synth: &SoundFontSynth) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        let data = &synth.font.sample_data;
+
+        for layer in self.layers.iter_mut().flatten() {
+            if layer.ended {
+                continue;
+            }
+
+            let mut val = layer.read(data);
+            if let Some(filter) = &mut layer.filter {
+                val = filter.process(val);
+            }
+            let val = val * layer.envelope.step() * synth.master_volume.get();
+            left += val * layer.gain_l;
+            right += val * layer.gain_r;
+
+            layer.pos += layer.step;
+            if layer.looping && layer.pos >= layer.loop_end as f64 {
+                // A short loop played high can step past it more than once.
+                let loop_start = layer.loop_start as f64;
+                let loop_len = (layer.loop_end - layer.loop_start) as f64;
+                layer.pos = loop_start + (layer.pos - loop_start).rem_euclid(loop_len);
+            } else if layer.pos >= layer.end as f64 {
+                layer.ended = true;
+            }
+            // Checked apart from the loop, which may wrap on every frame.
+            if layer.envelope.is_done() {
+                layer.ended = true;
+            }
+        }
+
+        (left, right)
+    }
+
+    fn notify_release(&mut self) {
+        for layer in self.layers.iter_mut().flatten() {
+            if layer.sustain_loop {
+                layer.looping = false;
+            }
+            layer.envelope.release();
+        }
+    }
+
+    fn is_done(&self, _synth: &SoundFontSynth) -> bool {
+        self.layers.iter().flatten().all(|layer| layer.ended)
+    }
+}
@@ -0,0 +1,407 @@
+use anyhow::{anyhow, bail, Result};
+use std::convert::TryInto;
+use std::fs;
+
+// Generator operators, see section 8.1.2 of the SoundFont 2.04 specification.
+pub const GEN_START_ADDRS_OFFSET: usize = 0;
+pub const GEN_END_ADDRS_OFFSET: usize = 1;
+pub const GEN_STARTLOOP_ADDRS_OFFSET: usize = 2;
+pub const GEN_ENDLOOP_ADDRS_OFFSET: usize = 3;
+pub const GEN_START_ADDRS_COARSE_OFFSET: usize = 4;
+pub const GEN_INITIAL_FILTER_FC: usize = 8;
+pub const GEN_INITIAL_FILTER_Q: usize = 9;
+pub const GEN_END_ADDRS_COARSE_OFFSET: usize = 12;
+pub const GEN_PAN: usize = 17;
+pub const GEN_DELAY_VOL_ENV: usize = 33;
+pub const GEN_ATTACK_VOL_ENV: usize = 34;
+pub const GEN_HOLD_VOL_ENV: usize = 35;
+pub const GEN_DECAY_VOL_ENV: usize = 36;
+pub const GEN_SUSTAIN_VOL_ENV: usize = 37;
+pub const GEN_RELEASE_VOL_ENV: usize = 38;
+pub const GEN_INSTRUMENT: usize = 41;
+pub const GEN_KEY_RANGE: usize = 43;
+pub const GEN_VEL_RANGE: usize = 44;
+pub const GEN_STARTLOOP_ADDRS_COARSE_OFFSET: usize = 45;
+pub const GEN_KEYNUM: usize = 46;
+pub const GEN_VELOCITY: usize = 47;
+pub const GEN_INITIAL_ATTENUATION: usize = 48;
+pub const GEN_ENDLOOP_ADDRS_COARSE_OFFSET: usize = 50;
+pub const GEN_COARSE_TUNE: usize = 51;
+pub const GEN_FINE_TUNE: usize = 52;
+pub const GEN_SAMPLE_ID: usize = 53;
+pub const GEN_SAMPLE_MODES: usize = 54;
+pub const GEN_SCALE_TUNING: usize = 56;
+pub const GEN_OVERRIDING_ROOT_KEY: usize = 58;
+pub const NUM_GENERATORS: usize = 61;
+
+/// Generator values of a zone. Ranges are stored as `lo | hi << 8`.
+#[derive(Debug, Clone)]
+pub struct Generators {
+    values: [Option<i16>; NUM_GENERATORS],
+}
+
+impl Generators {
+    fn new() -> Self {
+        Self {
+            values: [None; NUM_GENERATORS],
+        }
+    }
+
+    pub fn get(&self, gen: usize) -> Option<i16> {
+        self.values[gen]
+    }
+
+    pub fn range(&self, gen: usize) -> Option<(u8, u8)> {
+        self.values[gen].map(|v| (v as u16 as u8, (v as u16 >> 8) as u8))
+    }
+}
+
+/// The default value of a generator at instrument level.
+pub fn default_generator(gen: usize) -> i16 {
+    match gen {
+        GEN_INITIAL_FILTER_FC => 13500,
+        GEN_DELAY_VOL_ENV | GEN_ATTACK_VOL_ENV | GEN_HOLD_VOL_ENV | GEN_DECAY_VOL_ENV
+        | GEN_RELEASE_VOL_ENV => -12000,
+        GEN_KEYNUM | GEN_VELOCITY | GEN_OVERRIDING_ROOT_KEY => -1,
+        GEN_SCALE_TUNING => 100,
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Modulator {
+    pub src: u16,
+    pub dest: u16,
+    pub amount: i16,
+    pub amount_src: u16,
+    pub transform: u16,
+}
+
+impl Modulator {
+    /// Modulators are identical (and override each other) when everything
+    /// but the amount matches.
+    pub fn same_kind(&self, other: &Modulator) -> bool {
+        self.src == other.src
+            && self.dest == other.dest
+            && self.amount_src == other.amount_src
+            && self.transform == other.transform
+    }
+}
+
+/// The modulators every SoundFont synthesizer applies implicitly, limited to
+/// the ones with note-on sources.
+pub const DEFAULT_MODULATORS: [Modulator; 2] = [
+    // Velocity to attenuation, negative unipolar concave.
+    Modulator {
+        src: 0x0502,
+        dest: GEN_INITIAL_ATTENUATION as u16,
+        amount: 960,
+        amount_src: 0,
+        transform: 0,
+    },
+    // Velocity to filter cutoff, negative unipolar linear.
+    Modulator {
+        src: 0x0102,
+        dest: GEN_INITIAL_FILTER_FC as u16,
+        amount: -2400,
+        amount_src: 0,
+        transform: 0,
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub generators: Generators,
+    pub modulators: Vec<Modulator>,
+}
+
+impl Zone {
+    /// Whether a note falls in this zone's key and velocity range. Ranges
+    /// missing from this zone are taken from the global zone.
+    pub fn matches(&self, global: Option<&Zone>, key: u8, vel: u8) -> bool {
+        // `is_none_or` would need Rust 1.82.
+        #[allow(clippy::unnecessary_map_or)]
+        let in_range = |gen, v| {
+            let range = self
+                .generators
+                .range(gen)
+                .or_else(|| global.and_then(|g| g.generators.range(gen)));
+            range.map_or(true, |(lo, hi)| lo <= v && v <= hi)
+        };
+        in_range(GEN_KEY_RANGE, key) && in_range(GEN_VEL_RANGE, vel)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub program: u16,
+    pub bank: u16,
+    pub global_zone: Option<Zone>,
+    pub zones: Vec<Zone>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Instrument {
+    pub name: String,
+    pub global_zone: Option<Zone>,
+    pub zones: Vec<Zone>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SampleHeader {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+    pub start_loop: u32,
+    pub end_loop: u32,
+    pub sample_rate: u32,
+    pub original_pitch: u8,
+    pub pitch_correction: i8,
+}
+
+pub struct SoundFont {
+    pub presets: Vec<Preset>,
+    pub instruments: Vec<Instrument>,
+    pub samples: Vec<SampleHeader>,
+    /// All sample data in the file, indexed by the sample headers.
+    pub sample_data: Vec<f32>,
+}
+
+struct Chunk<'a> {
+    id: &'a [u8],
+    data: &'a [u8],
+}
+
+fn read_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn read_name(b: &[u8]) -> String {
+    let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+    String::from_utf8_lossy(&b[..end]).trim().to_string()
+}
+
+/// Splits a buffer into RIFF chunks.
+fn chunks(mut data: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let id = &data[0..4];
+        let len = read_u32(data, 4) as usize;
+        if data.len() < 8 + len {
+            bail!("truncated {} chunk", String::from_utf8_lossy(id));
+        }
+        chunks.push(Chunk {
+            id,
+            data: &data[8..8 + len],
+        });
+        // Chunks are padded to an even size.
+        data = &data[(8 + len + (len & 1)).min(data.len())..];
+    }
+    Ok(chunks)
+}
+
+/// Finds the LIST chunk of the given type and returns its sub-chunks.
+fn list<'a>(chunks: &[Chunk<'a>], list_type: &[u8]) -> Result<Vec<Chunk<'a>>> {
+    let list = chunks
+        .iter()
+        .find(|c| c.id == b"LIST" && c.data.len() >= 4 && &c.data[..4] == list_type)
+        .ok_or_else(|| anyhow!("missing {} list", String::from_utf8_lossy(list_type)))?;
+    self::chunks(&list.data[4..])
+}
+
+fn sub_chunk<'a>(chunks: &[Chunk<'a>], id: &[u8], record_size: usize) -> Result<&'a [u8]> {
+    let chunk = chunks
+        .iter()
+        .find(|c| c.id == id)
+        .ok_or_else(|| anyhow!("missing {} chunk", String::from_utf8_lossy(id)))?;
+    if chunk.data.len() % record_size != 0 {
+        bail!("{} chunk has invalid size", String::from_utf8_lossy(id));
+    }
+    Ok(chunk.data)
+}
+
+/// Builds the zones for the bag range [bag_start, bag_end) and splits off the
+/// global zone, which is the first zone if it lacks the terminal generator.
+fn zones(
+    bags: &[u8],
+    gens: &[u8],
+    mods: &[u8],
+    bag_start: usize,
+    bag_end: usize,
+    terminal_gen: usize,
+) -> Result<(Option<Zone>, Vec<Zone>)> {
+    let mut zones = Vec::new();
+    for bag in bag_start..bag_end {
+        let (gen_start, mod_start) = (read_u16(bags, bag * 4), read_u16(bags, bag * 4 + 2));
+        let (gen_end, mod_end) = (read_u16(bags, bag * 4 + 4), read_u16(bags, bag * 4 + 6));
+        if gen_end < gen_start || mod_end < mod_start || gen_end as usize * 4 > gens.len() {
+            bail!("invalid zone bag");
+        }
+
+        let mut generators = Generators::new();
+        for g in gen_start as usize..gen_end as usize {
+            let oper = read_u16(gens, g * 4) as usize;
+            if oper < NUM_GENERATORS {
+                generators.values[oper] = Some(read_u16(gens, g * 4 + 2) as i16);
+            }
+        }
+
+        let modulators = (mod_start as usize..mod_end as usize)
+            .filter(|m| m * 10 + 10 <= mods.len())
+            .map(|m| Modulator {
+                src: read_u16(mods, m * 10),
+                dest: read_u16(mods, m * 10 + 2),
+                amount: read_u16(mods, m * 10 + 4) as i16,
+                amount_src: read_u16(mods, m * 10 + 6),
+                transform: read_u16(mods, m * 10 + 8),
+            })
+            .collect();
+
+        zones.push(Zone {
+            generators,
+            modulators,
+        });
+    }
+
+    let global = match zones.first() {
+        Some(z) if z.generators.get(terminal_gen).is_none() => Some(zones.remove(0)),
+        _ => None,
+    };
+    // Zones without the terminal generator after the first are ignored.
+    zones.retain(|z| z.generators.get(terminal_gen).is_some());
+    Ok((global, zones))
+}
+
+impl SoundFont {
+    pub fn load(fname: &str) -> Result<Self> {
+        let file = fs::read(fname)?;
+        let riff = chunks(&file)?;
+        let riff = riff
+            .iter()
+            .find(|c| c.id == b"RIFF" && c.data.len() >= 4 && &c.data[..4] == b"sfbk")
+            .ok_or_else(|| anyhow!("{} is not a SoundFont 2 file", fname))?;
+        let top = chunks(&riff.data[4..])?;
+
+        let sdta = list(&top, b"sdta")?;
+        let smpl = sub_chunk(&sdta, b"smpl", 2)?;
+        let sample_data = smpl
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect();
+
+        let pdta = list(&top, b"pdta")?;
+        let phdr = sub_chunk(&pdta, b"phdr", 38)?;
+        let pbag = sub_chunk(&pdta, b"pbag", 4)?;
+        let pmod = sub_chunk(&pdta, b"pmod", 10)?;
+        let pgen = sub_chunk(&pdta, b"pgen", 4)?;
+        let inst = sub_chunk(&pdta, b"inst", 22)?;
+        let ibag = sub_chunk(&pdta, b"ibag", 4)?;
+        let imod = sub_chunk(&pdta, b"imod", 10)?;
+        let igen = sub_chunk(&pdta, b"igen", 4)?;
+        let shdr = sub_chunk(&pdta, b"shdr", 46)?;
+
+        // Every list ends with a terminal record that only marks the end of
+        // the previous record's bag range.
+        let num_presets = (phdr.len() / 38).saturating_sub(1);
+        let mut presets = Vec::with_capacity(num_presets);
+        for i in 0..num_presets {
+            let rec = &phdr[i * 38..];
+            let (bag_start, bag_end) =
+                (read_u16(rec, 24) as usize, read_u16(rec, 38 + 24) as usize);
+            if bag_end < bag_start || bag_end * 4 + 4 > pbag.len() {
+                bail!("invalid preset bag range");
+            }
+            let (global_zone, zones) = zones(pbag, pgen, pmod, bag_start, bag_end, GEN_INSTRUMENT)?;
+            presets.push(Preset {
+                name: read_name(&rec[..20]),
+                program: read_u16(rec, 20),
+                bank: read_u16(rec, 22),
+                global_zone,
+                zones,
+            });
+        }
+
+        let num_instruments = (inst.len() / 22).saturating_sub(1);
+        let mut instruments = Vec::with_capacity(num_instruments);
+        for i in 0..num_instruments {
+            let rec = &inst[i * 22..];
+            let (bag_start, bag_end) =
+                (read_u16(rec, 20) as usize, read_u16(rec, 22 + 20) as usize);
+            if bag_end < bag_start || bag_end * 4 + 4 > ibag.len() {
+                bail!("invalid instrument bag range");
+            }
+            let (global_zone, zones) = zones(ibag, igen, imod, bag_start, bag_end, GEN_SAMPLE_ID)?;
+            instruments.push(Instrument {
+                name: read_name(&rec[..20]),
+                global_zone,
+                zones,
+            });
+        }
+
+        let num_samples = (shdr.len() / 46).saturating_sub(1);
+        let samples = (0..num_samples)
+            .map(|i| {
+                let rec = &shdr[i * 46..];
+                SampleHeader {
+                    name: read_name(&rec[..20]),
+                    start: read_u32(rec, 20),
+                    end: read_u32(rec, 24),
+                    start_loop: read_u32(rec, 28),
+                    end_loop: read_u32(rec, 32),
+                    sample_rate: read_u32(rec, 36),
+                    original_pitch: rec[40],
+                    pitch_correction: rec[41] as i8,
+                }
+            })
+            .collect();
+
+        let sf = Self {
+            presets,
+            instruments,
+            samples,
+            sample_data,
+        };
+        sf.validate()?;
+        Ok(sf)
+    }
+
+    /// Checks that every index in the file points at something, so playback
+    /// never has to.
+    fn validate(&self) -> Result<()> {
+        for preset in &self.presets {
+            for zone in &preset.zones {
+                let inst = zone.generators.get(GEN_INSTRUMENT).unwrap() as u16 as usize;
+                if inst >= self.instruments.len() {
+                    bail!("preset {} refers to a missing instrument", preset.name);
+                }
+            }
+        }
+
+        for instrument in &self.instruments {
+            for zone in &instrument.zones {
+                let sample = zone.generators.get(GEN_SAMPLE_ID).unwrap() as u16 as usize;
+                if sample >= self.samples.len() {
+                    bail!("instrument {} refers to a missing sample", instrument.name);
+                }
+            }
+        }
+
+        for sample in &self.samples {
+            if sample.start > sample.end || sample.end as usize > self.sample_data.len() {
+                bail!("sample {} lies outside the sample data", sample.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn find_preset(&self, bank: u16, program: u16) -> Option<usize> {
+        self.presets
+            .iter()
+            .position(|p| p.bank == bank && p.program == program)
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::Result;
+use std::convert::TryInto;
+use std::net::UdpSocket;
+use std::sync::mpsc;
+use std::time::Instant;
+
+use crate::midi::{self, Event, EventContent};
+
+/// Where network events go: notes to the keyboard channel, parameters to the
+/// controller channel, and the parameter names OSC can set.
+pub struct Routing {
+    pub keyboard_channel: u8,
+    pub controller_channel: u8,
+    pub param_names: Vec<&'static str>,
+}
+
+/// Listens for events on a UDP socket, each datagram either an Open Sound
+/// Control packet or raw MIDI bytes. Understood OSC messages:
+///
+/// - `/note_on key vel` and `/note_off key`, in MIDI units.
+/// - `/param/<name> value` or `/param name value`, the position of the
+///   parameter in [0, 1].
+/// - `/program program`.
+/// - `/midi m`, a MIDI message in OSC's own type.
+pub fn listen(address: &str, routing: Routing, sender: mpsc::SyncSender<Event>) -> Result<()> {
+    let socket = UdpSocket::bind(address)?;
+    println!("listening for OSC and MIDI on udp {}", socket.local_addr()?);
+
+    std::thread::spawn(move || {
+        let mut sender = sender;
+        let mut buf = [0; 65536];
+        // Microseconds like the timestamps of MIDI ports, for timing MIDI clock.
+        let start = Instant::now();
+        loop {
+            let len = match socket.recv_from(&mut buf) {
+                Ok((len, _)) => len,
+                Err(err) => {
+                    eprintln!("receiving from UDP socket failed: {}", err);
+                    return;
+                }
+            };
+
+            let packet = &buf[..len];
+            let timestamp = start.elapsed().as_micros() as u64;
+            if packet.starts_with(b"/") || packet.starts_with(b"#bundle\0") {
+                if handle_osc_packet(packet, timestamp, &routing, &mut sender).is_none() {
+                    eprintln!("malformed OSC packet {:?}", packet);
+                }
+            } else {
+                handle_midi_packet(packet, timestamp, &mut sender);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// A datagram may hold several messages, each with its status byte.
+fn handle_midi_packet(mut packet: &[u8], timestamp: u64, sender: &mut mpsc::SyncSender<Event>) {
+    while let Some(&status) = packet.first() {
+        let len = match status {
+            0x80..=0xbf | 0xe0..=0xef | 0xf2 => 3,
+            0xc0..=0xdf | 0xf1 | 0xf3 => 2,
+            0xf0 => packet
+                .iter()
+                .position(|&b| b == 0xf7)
+                .map_or(packet.len(), |i| i + 1),
+            0xf4..=0xff => 1,
+            _ => {
+                eprintln!("MIDI over UDP without status byte {:?}", packet);
+                return;
+            }
+        };
+        let (message, rest) = packet.split_at(len.min(packet.len()));
+        midi::handle_message(timestamp, message, sender);
+        packet = rest;
+    }
+}
+
+#[derive(Debug)]
+enum Arg<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+    Midi([u8; 4]),
+}
+
+impl Arg<'_> {
+    /// NaN and infinities are rejected, they would stick in the smoothed
+    /// parameters and silence the synth for good.
+    fn number(&self) -> Option<f32> {
+        match *self {
+            Arg::Int(i) => Some(i as f32),
+            Arg::Float(f) => f.is_finite().then_some(f),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a null terminated string padded to a multiple of 4 bytes.
+fn read_string(data: &[u8]) -> Option<(&str, &[u8])> {
+    let len = data.iter().position(|&b| b == 0)?;
+    let s = std::str::from_utf8(&data[..len]).ok()?;
+    let padded = (len + 4) & !3;
+    Some((s, data.get(padded..)?))
+}
+
+fn read_u32(data: &[u8]) -> Option<(u32, &[u8])> {
+    let bytes = data.get(..4)?.try_into().ok()?;
+    Some((u32::from_be_bytes(bytes), &data[4..]))
+}
+
+/// Handles a message or a bundle, bundles are played right away whatever
+/// their time tag. `None` if the packet is malformed.
+fn handle_osc_packet(
+    packet: &[u8],
+    timestamp: u64,
+    routing: &Routing,
+    sender: &mut mpsc::SyncSender<Event>,
+) -> Option<()> {
+    if let Some(mut elements) = packet.strip_prefix(b"#bundle\0") {
+        elements = elements.get(8..)?; // Time tag.
+        while !elements.is_empty() {
+            let (size, rest) = read_u32(elements)?;
+            let element = rest.get(..size as usize)?;
+            handle_osc_packet(element, timestamp, routing, sender)?;
+            elements = &rest[size as usize..];
+        }
+        return Some(());
+    }
+
+    let (address, rest) = read_string(packet)?;
+    let (type_tags, mut rest) = read_string(rest)?;
+    let mut args = Vec::new();
+    for tag in type_tags.strip_prefix(',')?.chars() {
+        let (arg, next) = match tag {
+            'i' => read_u32(rest).map(|(v, next)| (Arg::Int(v as i32), next))?,
+            'f' => read_u32(rest).map(|(v, next)| (Arg::Float(f32::from_bits(v)), next))?,
+            's' => read_string(rest).map(|(s, next)| (Arg::Str(s), next))?,
+            'm' => read_u32(rest).map(|(v, next)| (Arg::Midi(v.to_be_bytes()), next))?,
+            _ => {
+                eprintln!("unsupported OSC argument type {} in {}", tag, address);
+                return Some(());
+            }
+        };
+        args.push(arg);
+        rest = next;
+    }
+
+    handle_osc_message(address, &args, timestamp, routing, sender);
+    Some(())
+}
+
+fn handle_osc_message(
+    address: &str,
+    args: &[Arg],
+    timestamp: u64,
+    routing: &Routing,
+    sender: &mut mpsc::SyncSender<Event>,
+) {
+    let midi_value = |idx: usize| -> Option<u8> {
+        Some(args.get(idx)?.number()?.round().clamp(0.0, 127.0) as u8)
+    };
+
+    let (channel, content) = match (address, args) {
+        ("/note_on", _) => match (midi_value(0), midi_value(1)) {
+            (Some(key), Some(vel)) => (routing.keyboard_channel, EventContent::NoteOn { key, vel }),
+            _ => return eprintln!("expected /note_on key vel"),
+        },
+        ("/note_off", _) => match midi_value(0) {
+            Some(key) => (
+                routing.keyboard_channel,
+                EventContent::NoteOff { key, vel: 0 },
+            ),
+            None => return eprintln!("expected /note_off key"),
+        },
+        ("/program", _) => match midi_value(0) {
+            Some(program) => (
+                routing.keyboard_channel,
+                EventContent::ProgramChange { program },
+            ),
+            None => return eprintln!("expected /program program"),
+        },
+        ("/midi", [Arg::Midi([_port, status, data1, data2])]) => {
+            midi::handle_message(timestamp, &[*status, *data1, *data2], sender);
+            return;
+        }
+        ("/param", [Arg::Str(name), value]) => match value.number() {
+            Some(value) => match param_change(name, value, routing) {
+                Some(event) => event,
+                None => return,
+            },
+            None => return eprintln!("expected /param name value"),
+        },
+        (address, [value]) if address.starts_with("/param/") => {
+            let name = &address["/param/".len()..];
+            match value.number() {
+                Some(value) => match param_change(name, value, routing) {
+                    Some(event) => event,
+                    None => return,
+                },
+                None => return eprintln!("expected {} value", address),
+            }
+        }
+        _ => return eprintln!("unknown OSC message {} {:?}", address, args),
+    };
+
+    let send_result = sender.send(Event {
+        timestamp,
+        channel,
+        content,
+    });
+    if let Err(err) = send_result {
+        eprintln!("failed to send OSC event, error: {:?}", err);
+    }
+}
+
+fn param_change(name: &str, value: f32, routing: &Routing) -> Option<(u8, EventContent)> {
+    match routing.param_names.iter().find(|n| **n == name) {
+        Some(name) => Some((
+            routing.controller_channel,
+            EventContent::Param { name, value },
+        )),
+        None => {
+            eprintln!("unknown parameter {}", name);
+            None
+        }
+    }
+}
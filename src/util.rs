@@ -16,6 +16,9 @@ pub trait ExtraFloatOps {
     fn mixexp(self, a: Self, b: Self) -> Self;
     fn db_to_gain(self) -> Self;
     fn gain_to_db(self) -> Self;
+    /// Rounds values far below audibility to zero, so decaying state never
+    /// reaches the (slow) denormal range.
+    fn flush_denormal(self) -> Self;
 }
 
 macro_rules! impl_extra_float_ops {
@@ -40,9 +43,84 @@ macro_rules! impl_extra_float_ops {
             fn gain_to_db(self) -> Self {
                 self.log10() * 20.0
             }
+
+            #[inline(always)]
+            fn flush_denormal(self) -> Self {
+                if self.abs() < 1e-20 {
+                    0.0
+                } else {
+                    self
+                }
+            }
         }
     };
 }
 
 impl_extra_float_ops!(f32);
 impl_extra_float_ops!(f64);
+
+/// Enables flush-to-zero and denormals-are-zero on the current thread while
+/// alive, restoring the previous floating point mode when dropped.
+pub struct DenormalGuard {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    old_mxcsr: u32,
+    #[cfg(target_arch = "aarch64")]
+    old_fpcr: u64,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl DenormalGuard {
+    const FTZ: u32 = 1 << 15;
+    const DAZ: u32 = 1 << 6;
+
+    pub fn new() -> Self {
+        let mut old_mxcsr: u32 = 0;
+        unsafe {
+            std::arch::asm!("stmxcsr [{}]", in(reg) &mut old_mxcsr);
+            let new_mxcsr = old_mxcsr | Self::FTZ | Self::DAZ;
+            std::arch::asm!("ldmxcsr [{}]", in(reg) &new_mxcsr);
+        }
+        Self { old_mxcsr }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        unsafe {
+            std::arch::asm!("ldmxcsr [{}]", in(reg) &self.old_mxcsr);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl DenormalGuard {
+    // FPCR.FZ flushes denormal inputs and outputs alike, it's off by default.
+    const FZ: u64 = 1 << 24;
+
+    pub fn new() -> Self {
+        let old_fpcr: u64;
+        unsafe {
+            std::arch::asm!("mrs {}, fpcr", out(reg) old_fpcr);
+            std::arch::asm!("msr fpcr, {}", in(reg) old_fpcr | Self::FZ);
+        }
+        Self { old_fpcr }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        unsafe {
+            std::arch::asm!("msr fpcr, {}", in(reg) self.old_fpcr);
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+impl DenormalGuard {
+    pub fn new() -> Self {
+        // A no-op, decaying state relies on `flush_denormal` here.
+        Self {}
+    }
+}
@@ -0,0 +1,19 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Reads a WAV file as interleaved floating point samples in [-1, 1].
+pub fn read_wav(path: impl AsRef<Path>) -> Result<(hound::WavSpec, Vec<f32>)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((spec, samples))
+}